@@ -4,6 +4,7 @@ signal connect_new_player
 signal player_connected
 signal enemy_connected
 signal change_team
+signal start_match
 
 onready var player_script = preload("res://native/Player.gd")
 onready var enemy_script = preload("res://native/Enemy.gd")
@@ -68,8 +69,7 @@ func _on_TeamManager_start_button_pressed(player, enemies):
 	player_scene_instance.set_name(player.network_name)
 	player_scene_instance.script = player_script
 	player_scene_instance.position = player.location
-	player_scene_instance.connect("player_move", network_scene, "_on_Player_player_move")
-	player_scene_instance.connect("player_kick", network_scene, "_on_Player_player_kick")
+	player_scene_instance.connect("player_input", network_scene, "_on_Player_player_input")
 	network_scene.connect("server_player_move", player_scene_instance, "_on_Network_server_player_move")
 	add_child(player_scene_instance)
 	
@@ -82,6 +82,8 @@ func _on_TeamManager_start_button_pressed(player, enemies):
 
 	self.remove_child(get_node("TeamManager"))
 
+	emit_signal("start_match")
+
 func _on_Network_enemy_disconnected(name):
 	for child in get_children():
 		if not child.get("network_name") == null and child.network_name == name:
@@ -5,9 +5,26 @@ var IP_SERVER = "164.90.191.192"
 var PORT_SERVER = 12350
 var PORT_CLIENT = 12353
 
+const PROTOCOL_VERSION = 6
+const CLIENT_BUILD = "godot-0.1.0"
+
 var socketUDP = PacketPeerUDP.new()
 
 var player_name = ""
+var player_nickname = ""
+var player_teams = {}
+var known_players = {}
+
+# Sent again in the handshake to get the same player back after the connection dropped
+var session_token = ""
+const RECONNECT_AFTER_MSEC = 2000
+var last_packet_msec = 0
+var last_handshake_msec = 0
+
+const SNAPSHOT_HISTORY = 32
+var snapshots = {}
+
+var input_sequence = 0
 
 signal player_connected(name, location)
 signal enemy_connected(name, location)
@@ -17,15 +34,118 @@ signal enemy_disconnected(name)
 signal ball_move(location)
 signal enemy_move(name, location)
 signal server_player_move(location)
+signal goal(team, score)
+signal match_phase_changed(phase, half, score, countdown)
+signal settings_changed(changes)
 
-var unreliable_packet_header = [84,9,0,0,0] #delivery: Unreliable, ordering: None
-var reliable_packet_header = [84,9,0,1,0,0,0,255,255,0,0,0,0] #delivery: Reliable, ordering: None
+# Mirrors shared::delivery on the server
+enum Delivery { RELIABLE_ORDERED, RELIABLE_UNORDERED, UNRELIABLE_SEQUENCED }
+const EVENT_STREAM = 0
+const SNAPSHOT_STREAM = 1
+const INPUT_STREAM = 2
 
-func define_player_name():
-	player_name = "{ip_client}:{port_client}".format({
-		"ip_client": IP_CLIENT,
-		"port_client": PORT_CLIENT
-	})
+const STANDARD_HEADER_SIZE = 5 # protocol, packet type, delivery, ordering
+const ACKED_HEADER_SIZE = 8 # sequence, ack sequence, ack field
+const ARRANGING_HEADER_SIZE = 3 # arranging id, stream
+
+# Reliable packets of the server are acknowledged in the header of every snapshot ack we send
+var local_sequence = 0
+var remote_sequence = 65535
+var received_sequences = {}
+# Arranging ids of the events already handled, the server resends events until they are acknowledged
+var handled_events = {}
+var last_snapshot_id = -1
+var arranging_ids = {}
+
+func delivery_of(action):
+	match action:
+		"WORLD_SNAPSHOT", "WORLD_SNAPSHOT_DELTA":
+			return [Delivery.UNRELIABLE_SEQUENCED, SNAPSHOT_STREAM]
+		"PLAYER_INPUT":
+			return [Delivery.UNRELIABLE_SEQUENCED, INPUT_STREAM]
+		"SNAPSHOT_ACK":
+			return [Delivery.RELIABLE_UNORDERED, null]
+	return [Delivery.RELIABLE_ORDERED, EVENT_STREAM]
+
+func u16_bytes(value):
+	return [(value >> 8) & 255, value & 255]
+
+func u32_bytes(value):
+	return [(value >> 24) & 255, (value >> 16) & 255, (value >> 8) & 255, value & 255]
+
+func read_u16(array_bytes, offset):
+	return (array_bytes[offset] << 8) | array_bytes[offset + 1]
+
+# a is newer than b, taking the wrap around of 16 bit sequences into account
+func sequence_greater_than(a, b):
+	return a != b and (a - b + 65536) % 65536 < 32768
+
+func ack_field():
+	var field = 0
+	for i in range(1, 33):
+		if received_sequences.has((remote_sequence - i + 65536) % 65536):
+			field |= 1 << (i - 1)
+	return field
+
+func next_arranging_id(stream):
+	var id = arranging_ids.get(stream, 0)
+	arranging_ids[stream] = (id + 1) % 65536
+	return id
+
+func packet_header(action):
+	var delivery = delivery_of(action)
+	match delivery[0]:
+		Delivery.UNRELIABLE_SEQUENCED:
+			return [84, 9, 0, 0, 1] + u16_bytes(next_arranging_id(delivery[1])) + [delivery[1]]
+		Delivery.RELIABLE_UNORDERED:
+			# Only snapshot acks carry the acked header, a lost one is superseded by the next tick
+			var header = [84, 9, 0, 1, 0] + u16_bytes(local_sequence) + u16_bytes(remote_sequence) + u32_bytes(ack_field())
+			local_sequence = (local_sequence + 1) % 65536
+			return header
+		_:
+			# Exempt from the shared policy: laminar only acknowledges our packets in the header of the
+			# next reliable packet of the server, which may never come, so there is no telling when to
+			# stop resending. Sent ordered without resending, one lost event would hold back every later
+			# one on the server, sent unreliable it only loses itself.
+			return [84, 9, 0, 0, 0]
+
+func acknowledge_packet(sequence):
+	received_sequences[sequence] = true
+	if sequence_greater_than(sequence, remote_sequence):
+		remote_sequence = sequence
+	for old_sequence in received_sequences.keys():
+		if sequence_greater_than(remote_sequence, (old_sequence + 32) % 65536):
+			received_sequences.erase(old_sequence)
+
+# Strips the laminar headers, returns null for packets that were already handled
+func read_packet(array_bytes):
+	var size = STANDARD_HEADER_SIZE
+	var reliable = array_bytes[3] == 1
+	var ordering = array_bytes[4]
+	if reliable:
+		acknowledge_packet(read_u16(array_bytes, size))
+		size += ACKED_HEADER_SIZE
+	if ordering != 0:
+		var arranging_id = read_u16(array_bytes, size)
+		if reliable:
+			if handled_events.has(arranging_id):
+				return null
+			handled_events[arranging_id] = true
+			for old_id in handled_events.keys():
+				if sequence_greater_than(arranging_id, (old_id + 1024) % 65536):
+					handled_events.erase(old_id)
+		else:
+			if last_snapshot_id != -1 and not sequence_greater_than(arranging_id, last_snapshot_id):
+				return null
+			last_snapshot_id = arranging_id
+		size += ARRANGING_HEADER_SIZE
+	for i in size:
+		array_bytes.remove(0)
+	return array_bytes
+
+# Player ids are numbers in JSON, nodes are named after them
+func player_key(id):
+	return str(int(id))
 
 func try_connect():
 	var recv_buf_size = 65536000;
@@ -36,40 +156,119 @@ func try_connect():
 		return try_connect()
 	return response
 
-func start_client(player_nickname):
+func start_client(nickname):
 	if try_connect() != OK:
 		printt("Error listening on port: " + str(PORT_CLIENT) + " in server: " + IP_SERVER)
 	else:
 		printt("Listening on port: " + str(PORT_CLIENT) + " in server: " + IP_SERVER)
-		define_player_name()
+		player_nickname = nickname
 		socketUDP.set_dest_address(IP_SERVER, PORT_SERVER)
-		send_packet(JSON.print({
-			"kind": "Connect", 
-			"payload": JSON.print({
-				"name": player_name,
-				"nickname": player_nickname
-			})
-		}), true)
+		send_handshake()
+
+func send_handshake():
+	var handshake = {
+		"protocol_version": PROTOCOL_VERSION,
+		"client_build": CLIENT_BUILD,
+		"capabilities": [],
+		"nickname": player_nickname
+	}
+	if session_token != "":
+		handshake["session_token"] = session_token
+	last_handshake_msec = OS.get_ticks_msec()
+	# A new connection on the server starts every stream over
+	handled_events = {}
+	last_snapshot_id = -1
+	send_packet(JSON.print({
+		"kind": "Connect", 
+		"payload": JSON.print(handshake)
+	}), "CONNECT")
+
+# Nothing heard from the server for a while, the address may have changed on the way
+func try_reconnect():
+	var now = OS.get_ticks_msec()
+	if session_token == "" or now - last_packet_msec < RECONNECT_AFTER_MSEC:
+		return
+	if now - last_handshake_msec < RECONNECT_AFTER_MSEC:
+		return
+	printt("No packets from server, resuming session")
+	send_handshake()
+
+func update_player_team(name, team):
+	if team == null or player_teams.get(name) == team:
+		return
+	player_teams[name] = team
+	emit_signal("changed_player_team", name, team)
+
+func apply_snapshot_delta(delta):
+	var baseline = snapshots.get(int(delta.get("baseline")))
+	if baseline == null:
+		return null
+	var snapshot = {
+		"tick": int(delta.get("tick")),
+		"ball": baseline.get("ball"),
+		"players": {}
+	}
+	if delta.get("ball") != null:
+		snapshot["ball"] = delta.get("ball")
+	var removed = []
+	for id in delta.get("removed"):
+		removed.append(player_key(id))
+	for name in baseline.get("players"):
+		if not removed.has(name):
+			snapshot["players"][name] = baseline.get("players").get(name)
+	for player in delta.get("players"):
+		snapshot["players"][player_key(player.get("id"))] = player
+	return snapshot
+
+func show_snapshot(snapshot):
+	snapshots[snapshot.get("tick")] = snapshot
+	if snapshots.size() > SNAPSHOT_HISTORY:
+		snapshots.erase(snapshots.keys().min())
+	acknowledge_snapshot(snapshot.get("tick"))
+
+	var ball_position = snapshot.get("ball").get("position")
+	emit_signal("ball_move", Vector2(ball_position.get("x"), ball_position.get("y")))
+	for name in snapshot.get("players"):
+		var position = snapshot.get("players").get(name).get("position")
+		if name == player_name:
+			emit_signal("server_player_move", Vector2(position.get("x"), position.get("y")))
+		else:
+			update_player_team(name, snapshot.get("players").get(name).get("team"))
+			emit_signal("enemy_move", name, Vector2(position.get("x"), position.get("y")))
+
+func next_input_sequence():
+	# Wraps like the u32 sequence on the server
+	input_sequence = (input_sequence + 1) % 4294967296
+	return input_sequence
+
+func acknowledge_snapshot(tick):
+	var stg = JSON.print({
+		"kind": "Data",
+		"payload": JSON.print({
+			"action": "SNAPSHOT_ACK",
+			"tick": tick
+		})
+	})
+	send_packet(stg, "SNAPSHOT_ACK")
 
 func _on_Game_connect_new_player(player_nickname):
 	start_client(player_nickname)
 	pass
 
-func send_packet(body, relieble = true):
-	var header
-	if relieble:
-		header = PoolByteArray(reliable_packet_header)
-	else:
-		header = PoolByteArray(unreliable_packet_header)
+func send_packet(body, action):
+	var header = PoolByteArray(packet_header(action))
 	for c in body:
 		header.append(ord(c))
 	socketUDP.put_packet(PoolByteArray(header))
 
 func _process(_delta):
+	if socketUDP.is_listening():
+		try_reconnect()
 	if socketUDP.get_available_packet_count() > 0:
-		var array_bytes = socketUDP.get_packet()
-		for i in unreliable_packet_header.size():
-			array_bytes.remove(0)
+		last_packet_msec = OS.get_ticks_msec()
+		var array_bytes = read_packet(socketUDP.get_packet())
+		if array_bytes == null:
+			return
 		var s = ""
 		for c in array_bytes:
 			s += char(c)
@@ -78,11 +277,39 @@ func _process(_delta):
 			var payload = JSON.parse(response.result.payload)
 			if payload.error == OK:
 				if payload.result.has("action"):
-					if payload.result.get("action") == "PLAYER_ADD_ACK":
+					if payload.result.get("action") == "HANDSHAKE_ACCEPTED":
+						player_name = player_key(payload.result.get("player_id"))
+						session_token = payload.result.get("session_token")
+						# A resumed player is still in its room
+						if not payload.result.get("resumed"):
+							send_action({ "action": "LIST_ROOMS" })
+					elif payload.result.get("action") == "ROOM_LIST":
+						# There is no room browser yet, the first open room is joined or a new one is created
+						var room_id = null
+						for room in payload.result.get("rooms"):
+							if not room.get("has_password"):
+								room_id = room.get("id")
+								break
+						if room_id != null:
+							send_action({ "action": "JOIN_ROOM", "room": room_id })
+						else:
+							send_action({ "action": "CREATE_ROOM" })
+					elif payload.result.get("action") == "ROOM_JOIN_REJECTED":
+						printt("Cannot join room: " + str(payload.result.get("reason")))
+					elif payload.result.get("action") == "MATCH_CONTROL_REJECTED":
+						printt("Only the host of the room can start or stop the match")
+					elif payload.result.get("action") == "HANDSHAKE_REJECTED":
+						printt("Connection rejected by server: " + str(payload.result.get("reason")) + ", server protocol version: " + str(payload.result.get("protocol_version")))
+					elif payload.result.get("action") == "PLAYER_ADD_ACK":
 						var position = payload.result.get("position");
-						var name = payload.result.get("name");
+						var name = player_key(payload.result.get("id"));
 						var nickname = payload.result.get("nickname");
-						if name == player_name:
+						var team = payload.result.get("team");
+						if known_players.has(name):
+							# Sent again after a resumed session, the player is already on screen
+							pass
+						elif name == player_name:
+							known_players[name] = nickname
 							emit_signal(
 								"player_connected",
 								name,
@@ -90,29 +317,57 @@ func _process(_delta):
 								Vector2(position.get("x"), position.get("y"))
 							)
 						else:
+							known_players[name] = nickname
 							emit_signal(
 								"enemy_connected",
 								name,
 								nickname,
 								Vector2(position.get("x"), position.get("y"))
 							)
+						update_player_team(name, team)
 					elif payload.result.get("action") == "CHANGE_PLAYER_TEAM_ACK":
-						var name = payload.result.get("name");
+						var name = player_key(payload.result.get("id"));
 						var team = payload.result.get("team");
-						emit_signal("changed_player_team", name, team)
+						update_player_team(name, team)
 					elif payload.result.get("action") == "PLAYER_DISCONNECT_ACK":
-						var name = payload.result.get("name");
+						var name = player_key(payload.result.get("id"));
+						known_players.erase(name)
 						emit_signal("enemy_disconnected", name)
-					elif payload.result.get("action") == "PLAYER_MOVED":
-						var player_position = payload.result.get("position");
-						emit_signal("server_player_move", Vector2(player_position.get("x"), player_position.get("y")))
-					elif payload.result.get("action") == "ENEMY_MOVED":
-						var name = payload.result.get("name");
-						var position = payload.result.get("position");
-						emit_signal("enemy_move", name, Vector2(position.get("x"), position.get("y")))
-					elif payload.result.get("action") == "BALL_MOVED":
-						var position = payload.result.get("position");
-						emit_signal("ball_move", Vector2(position.get("x"), position.get("y")))
+					elif payload.result.get("action") == "WORLD_SNAPSHOT":
+						var snapshot = {
+							"tick": int(payload.result.get("tick")),
+							"ball": payload.result.get("ball"),
+							"players": {}
+						}
+						for player in payload.result.get("players"):
+							snapshot["players"][player_key(player.get("id"))] = player
+						show_snapshot(snapshot)
+					elif payload.result.get("action") == "WORLD_SNAPSHOT_DELTA":
+						var snapshot = apply_snapshot_delta(payload.result)
+						if snapshot != null:
+							show_snapshot(snapshot)
+					elif payload.result.get("action") == "GOAL":
+						var team = payload.result.get("team");
+						var score = payload.result.get("score");
+						emit_signal("goal", team, score)
+					elif payload.result.get("action") == "MATCH_PHASE":
+						var phase = payload.result.get("phase");
+						var half = payload.result.get("half");
+						var score = payload.result.get("score");
+						var countdown = payload.result.get("countdown");
+						emit_signal("match_phase_changed", phase, half, score, countdown)
+					elif payload.result.get("action") == "SETTINGS_CHANGED":
+						var changes = payload.result.get("changes");
+						for change in changes:
+							print("Server setting %s changed from %s to %s" % [change.get("key"), change.get("old"), change.get("new")])
+						emit_signal("settings_changed", changes)
+
+func send_action(action):
+	var stg = JSON.print({
+		"kind": "Data",
+		"payload": JSON.print(action)
+	})
+	send_packet(stg, action.get("action"))
 
 func _on_Game_change_team(team):
 	if socketUDP.is_listening():
@@ -123,31 +378,28 @@ func _on_Game_change_team(team):
 				"team": team
 			})
 		})
-		send_packet(stg)
+		send_packet(stg, "CHANGE_PLAYER_TEAM")
 
-func _on_Player_player_move(location):
+func _on_Game_start_match():
 	if socketUDP.is_listening():
 		var stg = JSON.print({ 
 			"kind": "Data", 
 			"payload": JSON.print({
-				"action": "PLAYER_MOVED",
-				"position":{
-					"x":location.x,
-					"y":location.y
-				}
+				"action": "START_MATCH"
 			})
 		})
-		send_packet(stg)
+		send_packet(stg, "START_MATCH")
 
-func _on_Player_player_kick():
+func _on_Player_player_input(input):
 	if socketUDP.is_listening():
+		var payload = input.duplicate()
+		payload["action"] = "PLAYER_INPUT"
+		payload["sequence"] = next_input_sequence()
 		var stg = JSON.print({ 
 			"kind": "Data", 
-			"payload": JSON.print({
-				"action": "PLAYER_KICKED"
-			})
+			"payload": JSON.print(payload)
 		})
-		send_packet(stg)
+		send_packet(stg, "PLAYER_INPUT")
 
 func _exit_tree():
 	if socketUDP.is_listening():
@@ -157,5 +409,5 @@ func _exit_tree():
 				"action": "PLAYER_DISCONNECTED"
 			})
 		})
-		send_packet(stg)
+		send_packet(stg, "PLAYER_DISCONNECTED")
 	socketUDP.close()
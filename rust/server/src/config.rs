@@ -0,0 +1,505 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Serialize, Serializer, Deserialize};
+use serde_json::Value;
+use structopt::StructOpt;
+
+use shared::codec::Precision;
+
+pub const DEFAULT_PORT: u16 = 12350;
+pub const DEFAULT_TICK_RATE: u32 = 60;
+// A client that stays silent this long is disconnected
+pub const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the player of a disconnected client waits for it to come back with its session token
+pub const DEFAULT_RECONNECT_GRACE: Duration = Duration::from_secs(30);
+// Goal mouths are cut into the short sides of the field, which has to be higher than them
+pub const GOAL_WIDTH: f32 = 140.0;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(name = "server", about = "Game server of rball")]
+pub struct Options {
+    /// TOML file with the server configuration, built-in defaults are used for anything it leaves out
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, 0.0.0.0 accepts players from other machines
+    #[structopt(long)]
+    pub bind_address: Option<IpAddr>,
+
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+
+    #[structopt(long)]
+    pub tick_rate: Option<u32>,
+
+    /// Any other setting as `section.key=value`, e.g. `--set physics.kick_power=600`
+    #[structopt(long = "set", number_of_values = 1)]
+    pub overrides: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSettings {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub connection_timeout_ms: u64,
+    pub reconnect_grace_ms: u64,
+    // Snapshots are sent to clients at most this often, whatever the tick rate
+    pub telemetry_interval_ms: u64,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        NetworkSettings {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            connection_timeout_ms: DEFAULT_CONNECTION_TIMEOUT.as_millis() as u64,
+            reconnect_grace_ms: DEFAULT_RECONNECT_GRACE.as_millis() as u64,
+            telemetry_interval_ms: 30,
+        }
+    }
+}
+
+impl NetworkSettings {
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_millis(self.connection_timeout_ms)
+    }
+
+    pub fn reconnect_grace(&self) -> Duration {
+        Duration::from_millis(self.reconnect_grace_ms)
+    }
+
+    pub fn telemetry_interval(&self) -> Duration {
+        Duration::from_millis(self.telemetry_interval_ms)
+    }
+}
+
+/// Everything but the tick rate and the field size can be changed while the server runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PhysicsSettings {
+    pub tick_rate: u32,
+    pub field_width: f32,
+    pub field_height: f32,
+    pub ball_radius: f32,
+    pub player_radius: f32,
+    pub kick_power: f32,
+    // Impulse given to the ball when a moving player runs into it
+    pub push_power: f32,
+    // Largest distance between the centers of a player and the ball that still allows a kick
+    pub kick_range: f32,
+    pub ball_restitution: f32,
+    pub ball_linear_damping: f32,
+    pub ball_angular_damping: f32,
+}
+
+impl Default for PhysicsSettings {
+    fn default() -> Self {
+        PhysicsSettings {
+            tick_rate: DEFAULT_TICK_RATE,
+            field_width: 970.0,
+            field_height: 580.0,
+            ball_radius: 8.0,
+            player_radius: 15.0,
+            kick_power: 500.0,
+            push_power: 100.0,
+            kick_range: 30.0,
+            ball_restitution: 0.7,
+            ball_linear_damping: 0.5,
+            ball_angular_damping: 1.0,
+        }
+    }
+}
+
+/// How players move, the server turns the keys they hold into velocity with these.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MovementSettings {
+    // Pixels per second squared while a direction is held
+    pub acceleration: f32,
+    pub max_speed: f32,
+    // Fraction of the velocity lost per second once no direction is held
+    pub damping: f32,
+}
+
+impl Default for MovementSettings {
+    fn default() -> Self {
+        MovementSettings {
+            acceleration: 600.0,
+            max_speed: 100.0,
+            damping: 8.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomSettings {
+    // Rooms created by players count towards the limit too
+    pub max_rooms: usize,
+    // Opened on startup and kept open when everybody left, player rooms close once empty
+    pub permanent: Vec<String>,
+    // Keyed by the name of a permanent room, rooms created by players get theirs from their creator
+    pub access: BTreeMap<String, RoomAccessSettings>,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings {
+            max_rooms: 16,
+            permanent: vec![String::from("Main")],
+            access: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomAccessSettings {
+    // Settings are diffed and logged on reload, the password itself never shows up there
+    #[serde(serialize_with = "serialize_hidden")]
+    pub password: Option<String>,
+    // Players join with the invite code logged on startup, the room is left out of the room list
+    pub invite_only: bool,
+}
+
+fn serialize_hidden<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    value.as_ref().map(|_| "<hidden>").serialize(serializer)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub network: NetworkSettings,
+    pub rooms: RoomSettings,
+    pub physics: PhysicsSettings,
+    pub movement: MovementSettings,
+    pub precision: Precision,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingChange {
+    pub key: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl fmt::Display for SettingChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` changed from {} to {}", self.key, self.old, self.new)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(String),
+    InvalidOverride(String),
+    InvalidValue(&'static str, &'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "cannot read {:?}: {}", path, error),
+            ConfigError::Parse(reason) => write!(f, "malformed configuration: {}", reason),
+            ConfigError::InvalidOverride(assignment) => write!(f, "invalid override `{}`, expected `section.key=value`", assignment),
+            ConfigError::InvalidValue(key, reason) => write!(f, "`{}` {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    /// Defaults, then the config file, then `--set` overrides and finally the dedicated flags.
+    pub fn load(options: &Options) -> Result<ServerConfig, ConfigError> {
+        let mut table = match &options.config {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|error| ConfigError::Io(path.clone(), error))?
+                .parse::<toml::Value>()
+                .map_err(|error| ConfigError::Parse(error.to_string()))?,
+            None => toml::Value::Table(toml::value::Table::new()),
+        };
+
+        for assignment in options.overrides.iter() {
+            apply_override(&mut table, assignment)?;
+        }
+
+        let mut config: ServerConfig = table.try_into().map_err(|error| ConfigError::Parse(error.to_string()))?;
+
+        if let Some(bind_address) = options.bind_address {
+            config.network.bind_address = bind_address;
+        }
+        if let Some(port) = options.port {
+            config.network.port = port;
+        }
+        if let Some(tick_rate) = options.tick_rate {
+            config.physics.tick_rate = tick_rate;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let network = &self.network;
+        let physics = &self.physics;
+        let movement = &self.movement;
+
+        if network.port == 0 {
+            return Err(ConfigError::InvalidValue("network.port", "must not be zero, clients need a known port"));
+        }
+        if network.connection_timeout_ms == 0 {
+            return Err(ConfigError::InvalidValue("network.connection_timeout_ms", "must be greater than zero"));
+        }
+        if self.rooms.max_rooms == 0 || self.rooms.max_rooms < self.rooms.permanent.len() {
+            return Err(ConfigError::InvalidValue("rooms.max_rooms", "must leave space for every permanent room"));
+        }
+        if self.rooms.permanent.iter().any(|name| name.trim().is_empty()) {
+            return Err(ConfigError::InvalidValue("rooms.permanent", "must not contain empty names"));
+        }
+        if self.rooms.access.keys().any(|name| !self.rooms.permanent.contains(name)) {
+            return Err(ConfigError::InvalidValue("rooms.access", "must only name permanent rooms"));
+        }
+        if self.rooms.access.values().any(|access| access.password.as_deref() == Some("")) {
+            return Err(ConfigError::InvalidValue("rooms.access.password", "must not be empty, leave it out for a room without password"));
+        }
+        if physics.tick_rate == 0 || physics.tick_rate > 1000 {
+            return Err(ConfigError::InvalidValue("physics.tick_rate", "must be between 1 and 1000"));
+        }
+
+        for (key, value) in [
+            ("physics.field_width", physics.field_width),
+            ("physics.field_height", physics.field_height),
+            ("physics.ball_radius", physics.ball_radius),
+            ("physics.player_radius", physics.player_radius),
+            ("physics.kick_range", physics.kick_range),
+            ("movement.acceleration", movement.acceleration),
+            ("movement.max_speed", movement.max_speed),
+        ] {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(ConfigError::InvalidValue(key, "must be a finite number greater than zero"));
+            }
+        }
+
+        for (key, value) in [
+            ("physics.kick_power", physics.kick_power),
+            ("physics.push_power", physics.push_power),
+            ("physics.ball_restitution", physics.ball_restitution),
+            ("physics.ball_linear_damping", physics.ball_linear_damping),
+            ("physics.ball_angular_damping", physics.ball_angular_damping),
+            ("movement.damping", movement.damping),
+        ] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(ConfigError::InvalidValue(key, "must be a finite number that is not negative"));
+            }
+        }
+
+        if physics.field_height <= GOAL_WIDTH {
+            return Err(ConfigError::InvalidValue("physics.field_height", "must be larger than the goal mouth"));
+        }
+
+        // Quantized values are shifted into an i32
+        for (key, bits) in [
+            ("precision.position", self.precision.position),
+            ("precision.velocity", self.precision.velocity),
+            ("precision.rotation", self.precision.rotation),
+        ] {
+            if bits > 16 {
+                return Err(ConfigError::InvalidValue(key, "must be at most 16 bits"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes over the settings of a reloaded config that can change while the server runs.
+    /// Returns the changes that were taken over and the ones that only apply after a restart.
+    pub fn reload(&mut self, loaded: &ServerConfig) -> (Vec<SettingChange>, Vec<SettingChange>) {
+        let mut reloaded = self.clone();
+        reloaded.physics = PhysicsSettings {
+            tick_rate: self.physics.tick_rate,
+            field_width: self.physics.field_width,
+            field_height: self.physics.field_height,
+            ..loaded.physics.clone()
+        };
+        reloaded.movement = loaded.movement;
+
+        let applied = self.changes(&reloaded);
+        let ignored = reloaded.changes(loaded);
+        *self = reloaded;
+
+        (applied, ignored)
+    }
+
+    fn changes(&self, other: &ServerConfig) -> Vec<SettingChange> {
+        // Going through text keeps f32 settings as written, 0.7 instead of 0.699999988079071
+        let values = |config: &ServerConfig| -> Value { serde_json::from_str(&serde_json::to_string(config).unwrap()).unwrap() };
+        let (old, new) = (values(self), values(other));
+        let mut changes = Vec::new();
+
+        for (section, new_values) in new.as_object().unwrap() {
+            for (name, new_value) in new_values.as_object().unwrap() {
+                let old_value = &old[section][name];
+
+                if old_value != new_value {
+                    changes.push(SettingChange {
+                        key: format!("{}.{}", section, name),
+                        old: old_value.clone(),
+                        new: new_value.clone(),
+                    });
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+fn apply_override(table: &mut toml::Value, assignment: &str) -> Result<(), ConfigError> {
+    let invalid = || ConfigError::InvalidOverride(assignment.to_string());
+
+    let (key, raw_value) = assignment.split_once('=').ok_or_else(invalid)?;
+    let (key, raw_value) = (key.trim(), raw_value.trim());
+
+    // Anything that is not a TOML value on its own, like an address, is taken as a string
+    let value = format!("value = {}", raw_value).parse::<toml::Value>().ok()
+        .and_then(|parsed| parsed.get("value").cloned())
+        .unwrap_or_else(|| toml::Value::String(raw_value.to_string()));
+
+    let mut path: Vec<&str> = key.split('.').collect();
+    let name = path.pop().filter(|name| !name.is_empty()).ok_or_else(invalid)?;
+
+    let mut current = table;
+    for section in path {
+        current = current.as_table_mut().ok_or_else(invalid)?
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+    }
+
+    current.as_table_mut().ok_or_else(invalid)?.insert(name.to_string(), value);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Result<ServerConfig, ConfigError> {
+        let options = Options::from_iter(std::iter::once("server").chain(args.iter().copied()));
+        ServerConfig::load(&options)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = load(&[]).unwrap();
+
+        assert_eq!(config.network.port, DEFAULT_PORT);
+        assert_eq!(config.physics.tick_rate, DEFAULT_TICK_RATE);
+    }
+
+    #[test]
+    fn overrides_set_typed_values() {
+        let config = load(&["--set", "physics.kick_power=600", "--set", "movement.max_speed = 50"]).unwrap();
+
+        assert_eq!(config.physics.kick_power, 600.0);
+        assert_eq!(config.movement.max_speed, 50.0);
+    }
+
+    #[test]
+    fn overrides_fall_back_to_strings() {
+        let config = load(&["--set", "network.bind_address=0.0.0.0"]).unwrap();
+
+        assert_eq!(config.network.bind_address, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    }
+
+    #[test]
+    fn dedicated_flags_win_over_overrides() {
+        let config = load(&["--set", "network.port=4000", "--port", "5000"]).unwrap();
+
+        assert_eq!(config.network.port, 5000);
+    }
+
+    #[test]
+    fn malformed_overrides_are_rejected() {
+        for assignment in ["physics.kick_power", "=600", "physics.=600", "physics.kick_power.x=1"] {
+            assert!(load(&["--set", assignment]).is_err(), "accepted `{}`", assignment);
+        }
+
+        assert!(matches!(load(&["--set", "physics.kick_power"]), Err(ConfigError::InvalidOverride(_))));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(matches!(load(&["--set", "physics.kick_strength=600"]), Err(ConfigError::Parse(_))));
+        assert!(matches!(load(&["--set", "graphics.fps=60"]), Err(ConfigError::Parse(_))));
+        assert!(matches!(load(&["--set", "physics.kick_power=\"hard\""]), Err(ConfigError::Parse(_))));
+
+        let parsed = toml::from_str::<ServerConfig>("[network]\nport = 4000\nportt = 4001\n");
+        assert!(parsed.unwrap_err().to_string().contains("unknown field `portt`"));
+    }
+
+    #[test]
+    fn zero_tick_rate_is_rejected() {
+        assert!(matches!(load(&["--tick-rate", "0"]), Err(ConfigError::InvalidValue("physics.tick_rate", _))));
+        assert!(matches!(load(&["--set", "physics.tick_rate=0"]), Err(ConfigError::InvalidValue("physics.tick_rate", _))));
+    }
+
+    #[test]
+    fn precision_over_sixteen_bits_is_rejected() {
+        assert!(load(&["--set", "precision.position=16"]).is_ok());
+        assert!(matches!(load(&["--set", "precision.position=17"]), Err(ConfigError::InvalidValue("precision.position", _))));
+    }
+
+    #[test]
+    fn physics_values_are_validated() {
+        assert!(matches!(load(&["--set", "physics.ball_radius=0"]), Err(ConfigError::InvalidValue("physics.ball_radius", _))));
+        assert!(matches!(load(&["--set", "physics.kick_power=-1"]), Err(ConfigError::InvalidValue("physics.kick_power", _))));
+        assert!(matches!(load(&["--set", "physics.field_height=100"]), Err(ConfigError::InvalidValue("physics.field_height", _))));
+    }
+
+    #[test]
+    fn permanent_rooms_can_be_protected() {
+        let config = load(&["--set", "rooms.access.Main.password=secret", "--set", "rooms.access.Main.invite_only=true"]).unwrap();
+        let access = &config.rooms.access["Main"];
+
+        assert_eq!(access.password.as_deref(), Some("secret"));
+        assert!(access.invite_only);
+
+        // Changes are logged on reload, passwords must not end up there
+        assert!(!serde_json::to_string(&config).unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn access_settings_must_name_a_permanent_room() {
+        assert!(matches!(load(&["--set", "rooms.access.Lobby.password=secret"]), Err(ConfigError::InvalidValue("rooms.access", _))));
+        assert!(matches!(load(&["--set", "rooms.access.Main.password=\"\""]), Err(ConfigError::InvalidValue("rooms.access.password", _))));
+        assert!(matches!(load(&["--set", "rooms.access.Main.passwd=secret"]), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn reload_keeps_settings_that_need_a_restart() {
+        let mut config = load(&[]).unwrap();
+        let loaded = load(&["--set", "physics.kick_power=600", "--set", "physics.tick_rate=30"]).unwrap();
+
+        let (applied, ignored) = config.reload(&loaded);
+
+        assert_eq!(applied.iter().map(|change| change.key.as_str()).collect::<Vec<&str>>(), vec!["physics.kick_power"]);
+        assert_eq!(ignored.iter().map(|change| change.key.as_str()).collect::<Vec<&str>>(), vec!["physics.tick_rate"]);
+        assert_eq!(config.physics.kick_power, 600.0);
+        assert_eq!(config.physics.tick_rate, DEFAULT_TICK_RATE);
+    }
+}
@@ -1,29 +1,330 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::fs;
+use std::process;
 use std::thread::JoinHandle;
 use std::{thread};
-use std::str::FromStr;
+use std::convert::TryFrom;
 use std::time::{Duration, Instant};
 
-use log::{info, trace, error};
+use log::{info, debug, trace, warn, error};
 
 use rapier2d::prelude::*;
 
 use crossbeam_channel::{unbounded, Receiver, Sender, SendError};
 
-use laminar::{Packet, Socket, SocketEvent};
-use shared::message::{Position, Behavior, Message, MessageKind};
+use laminar::{Config, Packet, Socket, SocketEvent};
+use shared::codec::{BodySnapshot, Codec, PlayerSnapshot, Precision, SnapshotHistory, WorldSnapshot, BINARY_CODEC};
+use shared::delivery::Delivery;
+use shared::message::{Message, MessageKind, DecodeError, Handshake, HandshakeReply, PlayerId, PlayerInput, RejectReason, RoomId, PROTOCOL_VERSION};
+use shared::message::{Action, JoinRejectReason, LobbyReply, LobbyRequest, MatchPhase, RoomAccess, RoomInfo, TeamKind};
 
-use serde::{Serialize};
+use serde::{Serialize, Deserialize};
 use serde_json::{Value, json};
 
+use rand::seq::SliceRandom;
+use structopt::StructOpt;
+
+mod config;
+
+use config::{MovementSettings, Options, PhysicsSettings, RoomAccessSettings, ServerConfig, SettingChange, GOAL_WIDTH};
+
+#[derive(Debug, Clone)]
+struct Goal {
+    sensor: ColliderHandle,
+    area: ColliderHandle,
+    line_x: f32,
+    direction: f32,
+    scoring_team: TeamKind,
+}
+
+const GOAL_AREA_GROUP: u32 = 0b100;
+
+// Upper bound of commands / socket events handled in a single tick
+const COMMAND_BUDGET: usize = 512;
+const MALFORMED_PACKET_LIMIT: u32 = 10;
+// Wrong passwords and invite codes an address may send before it has to stay quiet for the backoff
+const FAILED_JOIN_LIMIT: u32 = 5;
+const FAILED_JOIN_BACKOFF: Duration = Duration::from_secs(60);
+const SNAPSHOT_HISTORY: usize = 32;
+const SERVER_CAPABILITIES: [&str; 1] = [BINARY_CODEC];
+
+const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const ROOM_NAME_LENGTH: usize = 32;
+// Every room plays on the same field for now
+const DEFAULT_MAP: &str = "Classic";
+// Letters and digits that cannot be mistaken for each other when read out loud
+const INVITE_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const INVITE_CODE_LENGTH: usize = 6;
+
+#[derive(Debug, Clone)]
+struct QueueMetrics {
+    name: String,
+    samples: u64,
+    total_depth: u64,
+    max_depth: usize,
+    processed: u64,
+    coalesced: u64,
+    last_report: Instant,
+}
+
+impl QueueMetrics {
+    fn new(name: String) -> Self {
+        QueueMetrics {
+            name,
+            samples: 0,
+            total_depth: 0,
+            max_depth: 0,
+            processed: 0,
+            coalesced: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    fn record_depth(&mut self, depth: usize) {
+        self.samples += 1;
+        self.total_depth += depth as u64;
+        self.max_depth = self.max_depth.max(depth);
+
+        if depth > COMMAND_BUDGET {
+            warn!("{} queue is over budget: {} pending, {} handled per tick", self.name, depth, COMMAND_BUDGET);
+        }
+    }
+
+    fn record_processed(&mut self, received: usize, processed: usize) {
+        self.processed += processed as u64;
+        self.coalesced += (received - processed) as u64;
+    }
+
+    fn report(&mut self) {
+        if self.last_report.elapsed() < METRICS_INTERVAL {
+            return;
+        }
+
+        debug!(
+            "{} queue: avg depth {:.2}, max depth {}, processed {}, coalesced {}",
+            self.name,
+            self.total_depth as f64 / self.samples.max(1) as f64,
+            self.max_depth,
+            self.processed,
+            self.coalesced
+        );
+
+        *self = QueueMetrics::new(self.name.clone());
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Field {
+    center: Vector<f32>,
+    half_extents: Vector<f32>,
+    center_circle_radius: f32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Score {
+    red_team: u32,
+    blue_team: u32,
+}
+
+impl Score {
+    fn add_goal(&mut self, team: &TeamKind) {
+        match team {
+            TeamKind::RedTeam => self.red_team += 1,
+            TeamKind::BlueTeam => self.blue_team += 1,
+            TeamKind::SpecTeam => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MatchSettings {
+    // Zero means the match is not limited by time / score
+    time_limit: Duration,
+    score_limit: u32,
+
+    kickoff_countdown: Duration,
+    goal_delay: Duration,
+    halftime_duration: Duration,
+    full_time_duration: Duration,
+}
+
+impl Default for MatchSettings {
+    fn default() -> Self {
+        MatchSettings {
+            time_limit: Duration::from_secs(180),
+            score_limit: 3,
+            kickoff_countdown: Duration::from_secs(3),
+            goal_delay: Duration::from_secs(3),
+            halftime_duration: Duration::from_secs(10),
+            full_time_duration: Duration::from_secs(10),
+        }
+    }
+}
+
+impl MovementSettings {
+    fn next_velocity(&self, velocity: Vector<f32>, input: &PlayerInput, dt: f32) -> Vector<f32> {
+        let (x, y) = input.direction();
+        let direction = vector![x, y];
+
+        // Steering towards the held direction also slows down any sideways movement
+        let velocity = if direction.norm() > 0.0 {
+            let target = direction.normalize() * self.max_speed;
+            velocity + (target - velocity).cap_magnitude(self.acceleration * dt)
+        } else {
+            velocity * (1.0 - self.damping * dt).max(0.0)
+        };
+
+        velocity.cap_magnitude(self.max_speed)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MatchState {
+    phase: MatchPhase,
+    settings: MatchSettings,
+    score: Score,
+    half: u8,
+    clock: Duration,
+    countdown: Duration,
+    kickoff_team: TeamKind,
+}
+
+impl MatchState {
+    fn new(settings: MatchSettings) -> Self {
+        MatchState {
+            phase: MatchPhase::Lobby,
+            settings,
+            score: Score::default(),
+            half: 1,
+            clock: Duration::ZERO,
+            countdown: Duration::ZERO,
+            kickoff_team: TeamKind::RedTeam,
+        }
+    }
+
+    fn enter(&mut self, phase: MatchPhase) {
+        self.phase = phase;
+        self.countdown = match phase {
+            MatchPhase::Kickoff => self.settings.kickoff_countdown,
+            MatchPhase::GoalScored => self.settings.goal_delay,
+            MatchPhase::Halftime => self.settings.halftime_duration,
+            MatchPhase::FullTime => self.settings.full_time_duration,
+            MatchPhase::Lobby | MatchPhase::Playing => Duration::ZERO,
+        };
+    }
+
+    fn start(&mut self, settings: MatchSettings) -> bool {
+        if self.phase != MatchPhase::Lobby {
+            return false;
+        }
+
+        *self = MatchState::new(settings);
+        self.enter(MatchPhase::Kickoff);
+
+        true
+    }
+
+    fn stop(&mut self) -> bool {
+        if self.phase == MatchPhase::Lobby {
+            return false;
+        }
+
+        self.enter(MatchPhase::Lobby);
+
+        true
+    }
+
+    fn goal(&mut self, team: &TeamKind) -> bool {
+        if self.phase != MatchPhase::Playing {
+            return false;
+        }
+
+        self.score.add_goal(team);
+        self.kickoff_team = team.opponent();
+
+        let limit = self.settings.score_limit;
+        if limit > 0 && (self.score.red_team >= limit || self.score.blue_team >= limit) {
+            self.enter(MatchPhase::FullTime);
+        } else {
+            self.enter(MatchPhase::GoalScored);
+        }
+
+        true
+    }
+
+    // Advances the match clock, returns true when the phase has changed
+    fn update(&mut self, dt: Duration) -> bool {
+        match self.phase {
+            MatchPhase::Lobby => false,
+            MatchPhase::Playing => {
+                self.clock += dt;
+
+                let time_limit = self.settings.time_limit;
+                if time_limit.is_zero() {
+                    return false;
+                }
+
+                if self.half == 1 && self.clock >= time_limit / 2 {
+                    self.enter(MatchPhase::Halftime);
+                    true
+                } else if self.clock >= time_limit {
+                    self.enter(MatchPhase::FullTime);
+                    true
+                } else {
+                    false
+                }
+            },
+            _ => {
+                self.countdown = self.countdown.saturating_sub(dt);
+                if !self.countdown.is_zero() {
+                    return false;
+                }
+
+                match self.phase {
+                    MatchPhase::Kickoff => self.enter(MatchPhase::Playing),
+                    MatchPhase::GoalScored => self.enter(MatchPhase::Kickoff),
+                    MatchPhase::Halftime => {
+                        self.half = 2;
+                        self.kickoff_team = TeamKind::BlueTeam;
+                        self.enter(MatchPhase::Kickoff);
+                    },
+                    _ => self.enter(MatchPhase::Lobby),
+                }
+
+                true
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 struct PhysicsEngine {
     channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)>,
     handles: HashMap<String, RigidBodyHandle>,
+    players: HashMap<PlayerId, RigidBodyHandle>,
     bodies: RigidBodySet,
     colliders: ColliderSet,
     joints: JointSet,
-    islands: IslandManager
+    islands: IslandManager,
+    teams: HashMap<PlayerId, TeamKind>,
+    player_inputs: HashMap<PlayerId, PlayerInput>,
+    // Kinematic velocities are reset after every step, so the velocity of each player is kept here
+    player_velocities: HashMap<PlayerId, Vector<f32>>,
+    pending_kicks: HashSet<PlayerId>,
+    input_sequences: HashMap<PlayerId, u32>,
+    settings: PhysicsSettings,
+    movement: MovementSettings,
+    field: Field,
+    goals: Vec<Goal>,
+    match_state: MatchState,
+    kickoff_pending: bool,
+    tick: u64,
+    // Cleared when the room closes, the simulation thread ends after the current tick
+    running: bool,
+    metrics: QueueMetrics,
 }
 
 impl PhysicsEngine {
@@ -32,7 +333,7 @@ impl PhysicsEngine {
          * Ground.
          */
         let ground_size = 5.0;
-        let ground_area = vector![970.0, 580.0];
+        let ground_area = vector![self.settings.field_width, self.settings.field_height];
 
         /*
          * Frame.
@@ -40,17 +341,79 @@ impl PhysicsEngine {
         let offset_x = 27.0;
         let offset_y = -10.0;
 
-        let rigid_body = RigidBodyBuilder::new_static()
-            .translation(vector![offset_x, ground_area.y / 2.0 - offset_y])
-            .build();
-        let collider = ColliderBuilder::cuboid(ground_size, ground_area.y / 2.0).build();
-        self.insert_body(String::from("left"), rigid_body, collider);
+        /*
+         * Goals.
+         */
+        let goal_width = GOAL_WIDTH;
+        let goal_depth = 40.0;
+        let goal_area_depth = 60.0;
+        let center_y = ground_area.y / 2.0 - offset_y;
+        let post_size = (ground_area.y - goal_width) / 4.0;
+
+        self.field = Field {
+            center: vector![ground_area.x / 2.0 + offset_x, center_y],
+            half_extents: ground_area / 2.0,
+            center_circle_radius: 80.0,
+        };
 
-        let rigid_body = RigidBodyBuilder::new_static()
-            .translation(vector![ground_area.x + offset_x, ground_area.y / 2.0 - offset_y])
-            .build();
-        let collider = ColliderBuilder::cuboid(ground_size, ground_area.y / 2.0).build();
-        self.insert_body(String::from("right"), rigid_body, collider);
+        for (side, line_x, direction, scoring_team) in [
+            ("left", offset_x, -1.0, TeamKind::BlueTeam),
+            ("right", ground_area.x + offset_x, 1.0, TeamKind::RedTeam),
+        ] {
+            // Wall segments above and below the goal mouth
+            for (segment, sign) in [("top", -1.0), ("bottom", 1.0)] {
+                let rigid_body = RigidBodyBuilder::new_static()
+                    .translation(vector![line_x, center_y + sign * (goal_width / 2.0 + post_size)])
+                    .build();
+                let collider = ColliderBuilder::cuboid(ground_size, post_size).build();
+                self.insert_body(format!("{}_{}", side, segment), rigid_body, collider);
+            }
+
+            // Net around the goal mouth, so the ball stays inside after crossing the line
+            let net_x = line_x + direction * goal_depth;
+            let rigid_body = RigidBodyBuilder::new_static()
+                .translation(vector![net_x, center_y])
+                .build();
+            let collider = ColliderBuilder::cuboid(ground_size, goal_width / 2.0 + ground_size * 2.0).build();
+            self.insert_body(format!("{}_goal_back", side), rigid_body, collider);
+
+            for (segment, sign) in [("top", -1.0), ("bottom", 1.0)] {
+                let rigid_body = RigidBodyBuilder::new_static()
+                    .translation(vector![line_x + direction * goal_depth / 2.0, center_y + sign * (goal_width / 2.0 + ground_size)])
+                    .build();
+                let collider = ColliderBuilder::cuboid(goal_depth / 2.0, ground_size).build();
+                self.insert_body(format!("{}_goal_{}", side, segment), rigid_body, collider);
+            }
+
+            // Sensor behind the goal line
+            let rigid_body = RigidBodyBuilder::new_static()
+                .translation(vector![line_x + direction * goal_depth / 2.0, center_y])
+                .build();
+            let collider = ColliderBuilder::cuboid(goal_depth / 2.0, goal_width / 2.0)
+                .sensor(true)
+                .build();
+            let handle = self.insert_body(format!("{}_goal", side), rigid_body, collider);
+            let sensor = self.bodies[handle].colliders()[0];
+
+            // Goal area in front of the goal, only the attacking team is tested against it
+            let rigid_body = RigidBodyBuilder::new_static()
+                .translation(vector![line_x - direction * goal_area_depth / 2.0, center_y])
+                .build();
+            let collider = ColliderBuilder::cuboid(goal_area_depth / 2.0, goal_width / 2.0 + goal_area_depth)
+                .sensor(true)
+                .collision_groups(InteractionGroups::new(GOAL_AREA_GROUP, scoring_team.collision_group()))
+                .build();
+            let handle = self.insert_body(format!("{}_goal_area", side), rigid_body, collider);
+            let area = self.bodies[handle].colliders()[0];
+
+            self.goals.push(Goal {
+                sensor,
+                area,
+                line_x,
+                direction,
+                scoring_team,
+            });
+        }
 
         let rigid_body = RigidBodyBuilder::new_static()
             .translation(vector![ground_area.x / 2.0 + offset_x, 0.0 - offset_y])
@@ -65,15 +428,15 @@ impl PhysicsEngine {
         self.insert_body(String::from("bottom"), rigid_body, collider);
 
         // Build ball
-        let rad = 8.0;
+        let rad = self.settings.ball_radius;
         let ball_body = RigidBodyBuilder::new_dynamic()
-            .translation(vector![250.0, 250.0])
-            .linear_damping(0.5)
-            .angular_damping(1.0)
+            .translation(self.field.center)
+            .linear_damping(self.settings.ball_linear_damping)
+            .angular_damping(self.settings.ball_angular_damping)
             .build();
         let collider = ColliderBuilder::ball(rad)
             .active_events(ActiveEvents::CONTACT_EVENTS | ActiveEvents::INTERSECTION_EVENTS)
-            .restitution(0.7)
+            .restitution(self.settings.ball_restitution)
             .active_collision_types(ActiveCollisionTypes::default() | ActiveCollisionTypes::KINEMATIC_STATIC)
             .build();
         self.insert_body(String::from("ball"), ball_body, collider);
@@ -94,10 +457,10 @@ impl PhysicsEngine {
         handle
     }
 
-    fn remove_body(&mut self, name: &str) -> Result<(), &'static str> {
+    fn remove_player_body(&mut self, id: PlayerId) -> Result<(), &'static str> {
         let rigid_body_set = &mut self.bodies;
 
-        if let Some(rigid_body_handle) = self.handles.remove(name) {
+        if let Some(rigid_body_handle) = self.players.remove(&id) {
             rigid_body_set.remove(
                 rigid_body_handle,
                 &mut self.islands,
@@ -111,11 +474,290 @@ impl PhysicsEngine {
         Err("Body not found")
     }
 
+    fn detect_goal(&self, narrow_phase: &NarrowPhase) -> Option<TeamKind> {
+        let ball_rigid_body_handle = self.handles.get("ball")?;
+        let ball_rigid_body = self.bodies.get(*ball_rigid_body_handle)?;
+        let ball_collider_handle = ball_rigid_body.colliders()[0];
+        let ball_radius = self.colliders[ball_collider_handle].shape().as_ball()?.radius;
+        let ball_translation = ball_rigid_body.translation();
+
+        for goal in self.goals.iter() {
+            if narrow_phase.intersection_pair(ball_collider_handle, goal.sensor) != Some(true) {
+                continue;
+            }
+
+            // The whole ball has to be behind the goal line
+            if (ball_translation.x - goal.line_x) * goal.direction > ball_radius {
+                return Some(goal.scoring_team.clone());
+            }
+        }
+
+        None
+    }
+
+    fn reset_ball(&mut self) {
+        if let Some(ball_rigid_body_handle) = self.handles.get("ball") {
+            let ball_rigid_body = self.bodies.get_mut(*ball_rigid_body_handle).unwrap();
+            ball_rigid_body.set_translation(self.field.center, true);
+            ball_rigid_body.set_linvel(vector![0.0, 0.0], true);
+            ball_rigid_body.set_angvel(0.0, true);
+        }
+    }
+
+    fn body_radius(&self, rigid_body_handle: RigidBodyHandle) -> f32 {
+        let collider_handle = self.bodies[rigid_body_handle].colliders()[0];
+        self.colliders[collider_handle].shape().as_ball().map_or(0.0, |ball| ball.radius)
+    }
+
+    fn spawn_position(&self, team: &TeamKind, index: usize) -> Vector<f32> {
+        let field = &self.field;
+
+        match team {
+            // Spectators are parked in a row below the field
+            TeamKind::SpecTeam => vector![
+                field.center.x - field.half_extents.x + 40.0 * (index as f32 + 1.0),
+                field.center.y + field.half_extents.y + 50.0
+            ],
+            _ => {
+                let column = (index / 3).min(2) as f32;
+                let row = [0.0, -1.0, 1.0][index % 3];
+
+                vector![
+                    field.center.x + team.side() * field.half_extents.x * (0.3 + 0.25 * column),
+                    field.center.y + row * field.half_extents.y * 0.4
+                ]
+            }
+        }
+    }
+
+    fn add_player_body(&mut self, id: PlayerId, translation: Vector<f32>, team: &TeamKind) -> RigidBodyHandle {
+        let rad = self.settings.player_radius;
+        let player_body = RigidBodyBuilder::new_kinematic_velocity_based()
+            .translation(translation)
+            .build();
+        let collider = ColliderBuilder::ball(rad)
+            .active_events(ActiveEvents::CONTACT_EVENTS | ActiveEvents::INTERSECTION_EVENTS)
+            .sensor(true)
+            .user_data(team.user_data())
+            .collision_groups(InteractionGroups::new(team.collision_group(), u32::MAX))
+            .active_collision_types(ActiveCollisionTypes::default() | ActiveCollisionTypes::KINEMATIC_STATIC)
+            .build();
+
+        let rigid_body_set = &mut self.bodies;
+
+        let handle = rigid_body_set.insert(player_body);
+        self.colliders.insert_with_parent(collider, handle, rigid_body_set);
+        self.players.insert(id, handle);
+
+        handle
+    }
+
+    // Brings the bodies that already exist in line with reloaded settings
+    fn apply_settings(&mut self) {
+        if let Some(ball_rigid_body_handle) = self.handles.get("ball") {
+            let ball_rigid_body = self.bodies.get_mut(*ball_rigid_body_handle).unwrap();
+            ball_rigid_body.set_linear_damping(self.settings.ball_linear_damping);
+            ball_rigid_body.set_angular_damping(self.settings.ball_angular_damping);
+
+            let ball_collider = &mut self.colliders[ball_rigid_body.colliders()[0]];
+            ball_collider.set_restitution(self.settings.ball_restitution);
+            ball_collider.set_shape(SharedShape::ball(self.settings.ball_radius));
+        }
+
+        for rigid_body_handle in self.players.values() {
+            let player_collider = &mut self.colliders[self.bodies[*rigid_body_handle].colliders()[0]];
+            player_collider.set_shape(SharedShape::ball(self.settings.player_radius));
+        }
+    }
+
+    // Positions of all players, spectators get their slot off the field
+    fn player_positions(&self) -> HashMap<PlayerId, Vector<f32>> {
+        let mut players: Vec<PlayerId> = self.teams.keys().copied().collect();
+        players.sort();
+
+        let mut spectators = 0;
+        let mut positions = HashMap::new();
+
+        for id in players {
+            let translation = match self.players.get(&id) {
+                Some(rigid_body_handle) => *self.bodies[*rigid_body_handle].translation(),
+                None => {
+                    spectators += 1;
+                    self.spawn_position(&TeamKind::SpecTeam, spectators - 1)
+                }
+            };
+
+            positions.insert(id, translation);
+        }
+
+        positions
+    }
+
+    fn place_player(&mut self, id: PlayerId, translation: Vector<f32>) {
+        if let Some(rigid_body_handle) = self.players.get(&id) {
+            let rigid_body = self.bodies.get_mut(*rigid_body_handle).unwrap();
+            rigid_body.set_translation(translation, true);
+            rigid_body.set_linvel(vector![0.0, 0.0], true);
+        }
+    }
+
+    fn kickoff(&mut self) {
+        self.reset_ball();
+        self.freeze_players();
+
+        let mut players: Vec<(PlayerId, TeamKind)> = self.teams.iter()
+            .map(|(id, team)| (*id, team.clone()))
+            .collect();
+        players.sort_by_key(|(id, _)| *id);
+
+        let mut slots: HashMap<TeamKind, usize> = HashMap::new();
+        for (id, team) in players {
+            let slot = slots.entry(team.clone()).or_insert(0);
+            let translation = self.spawn_position(&team, *slot);
+            *slot += 1;
+
+            self.place_player(id, translation);
+        }
+
+        self.kickoff_pending = true;
+    }
+
+    // Attackers are pushed back out of the goal area of the defending team
+    fn enforce_goal_areas(&mut self, narrow_phase: &NarrowPhase) {
+        let mut intruders = Vec::new();
+
+        for goal in self.goals.iter() {
+            let area = &self.colliders[goal.area];
+            let area_edge_x = area.translation().x - goal.direction * area.shape().as_cuboid().unwrap().half_extents.x;
+
+            for (collider1, collider2, intersecting) in narrow_phase.intersections_with(goal.area) {
+                let player_collider_handle = if collider1 == goal.area { collider2 } else { collider1 };
+                let player_collider = &self.colliders[player_collider_handle];
+
+                if !intersecting || TeamKind::from_user_data(player_collider.user_data).is_none() {
+                    continue;
+                }
+
+                if let Some(rigid_body_handle) = player_collider.parent() {
+                    let radius = player_collider.shape().as_ball().map_or(0.0, |ball| ball.radius);
+                    intruders.push((rigid_body_handle, area_edge_x - goal.direction * radius));
+                }
+            }
+        }
+
+        for (rigid_body_handle, x) in intruders {
+            let rigid_body = self.bodies.get_mut(rigid_body_handle).unwrap();
+            let mut translation = *rigid_body.translation();
+            translation.x = x;
+            rigid_body.set_translation(translation, true);
+        }
+    }
+
+    // Until the ball is touched both teams stay on their own half
+    // and the team that is not kicking off stays out of the center circle
+    fn enforce_kickoff(&mut self) {
+        if !self.kickoff_pending {
+            return;
+        }
+
+        let field = self.field;
+        let kickoff_team = self.match_state.kickoff_team.clone();
+
+        for (id, team) in self.teams.iter() {
+            if *team == TeamKind::SpecTeam {
+                continue;
+            }
+
+            let rigid_body_handle = match self.players.get(id) {
+                Some(rigid_body_handle) => *rigid_body_handle,
+                None => continue,
+            };
+            let radius = self.body_radius(rigid_body_handle);
+            let rigid_body = self.bodies.get_mut(rigid_body_handle).unwrap();
+            let mut translation = *rigid_body.translation();
+
+            let side = team.side();
+            if (translation.x - field.center.x) * side < radius {
+                translation.x = field.center.x + side * radius;
+            }
+
+            if *team != kickoff_team {
+                let offset = translation - field.center;
+                let min_distance = field.center_circle_radius + radius;
+
+                if offset.norm() < min_distance {
+                    translation = field.center + offset.normalize() * min_distance;
+                }
+            }
+
+            if translation != *rigid_body.translation() {
+                rigid_body.set_translation(translation, true);
+            }
+        }
+    }
+
+    fn freeze_players(&mut self) {
+        for (_, rigid_body) in self.bodies.iter_mut() {
+            if rigid_body.is_kinematic() {
+                rigid_body.set_linvel(vector![0.0, 0.0], true);
+            }
+        }
+
+        self.player_velocities.clear();
+    }
+
+    fn handle_goal(&mut self, narrow_phase: &NarrowPhase) {
+        if let Some(team) = self.detect_goal(narrow_phase) {
+            match self.match_state.phase {
+                MatchPhase::Lobby => self.reset_ball(),
+                MatchPhase::Playing => {
+                    self.match_state.goal(&team);
+                    info!("Goal for {:?}, score: {:?}", team, self.match_state.score);
+
+                    let (sender, _) = &self.channels.get("network").unwrap();
+                    sender.send(NetworkCommand::Goal {
+                        team,
+                        score: self.match_state.score.clone(),
+                    }).unwrap();
+
+                    self.handle_phase_change();
+                },
+                _ => ()
+            }
+        }
+    }
+
+    fn handle_phase_change(&mut self) {
+        info!("Match phase changed to {:?}", self.match_state.phase);
+
+        match self.match_state.phase {
+            MatchPhase::Kickoff => self.kickoff(),
+            MatchPhase::Lobby => self.kickoff_pending = false,
+            _ => ()
+        }
+
+        let (sender, _) = &self.channels.get("network").unwrap();
+        sender.send(NetworkCommand::MatchPhaseChanged {
+            phase: self.match_state.phase,
+            half: self.match_state.half,
+            score: self.match_state.score.clone(),
+            clock: self.match_state.clock,
+            countdown: self.match_state.countdown,
+        }).unwrap();
+    }
+
     fn start_simulation(&mut self) {
         self.initialize();
 
+        let tick_duration = Duration::from_secs_f64(1.0 / self.settings.tick_rate as f64);
+        // Never catch up more than a few ticks at once, otherwise a stall would snowball
+        let max_accumulated = tick_duration * 5;
+
         let gravity = vector![0.0, 0.0];
-        let integration_parameters = IntegrationParameters::default();
+        let integration_parameters = IntegrationParameters {
+            dt: tick_duration.as_secs_f32(),
+            ..IntegrationParameters::default()
+        };
         let mut broad_phase = BroadPhase::new();
         let mut narrow_phase = NarrowPhase::new();
         let mut ccd_solver = CCDSolver::new();
@@ -124,32 +766,82 @@ impl PhysicsEngine {
 
         let mut physics_pipeline = PhysicsPipeline::new();
 
-        let delay = Duration::from_millis(1);
+        let mut accumulator = Duration::ZERO;
+        let mut last_update = Instant::now();
 
-        loop {
-            self.handle_command(&narrow_phase);
+        while self.running {
+            let now = Instant::now();
+            accumulator += now - last_update;
+            last_update = now;
 
-            physics_pipeline.step(
-                &gravity,
-                &integration_parameters,
-                &mut self.islands,
-                &mut broad_phase,
-                &mut narrow_phase,
-                &mut self.bodies,
-                &mut self.colliders,
-                &mut self.joints,
-                &mut ccd_solver,
-                &physics_hooks,
-                &event_handler,
-            );
+            if accumulator > max_accumulated {
+                warn!("Simulation is running behind, skipping {:?}", accumulator - max_accumulated);
+                accumulator = max_accumulated;
+            }
+
+            while accumulator >= tick_duration {
+                accumulator -= tick_duration;
+                self.tick += 1;
+
+                if self.match_state.update(tick_duration) {
+                    self.handle_phase_change();
+                }
+
+                self.handle_commands();
+                self.apply_inputs(&narrow_phase, integration_parameters.dt);
+
+                physics_pipeline.step(
+                    &gravity,
+                    &integration_parameters,
+                    &mut self.islands,
+                    &mut broad_phase,
+                    &mut narrow_phase,
+                    &mut self.bodies,
+                    &mut self.colliders,
+                    &mut self.joints,
+                    &mut ccd_solver,
+                    &physics_hooks,
+                    &event_handler,
+                );
+
+                self.enforce_kickoff();
+                self.enforce_goal_areas(&narrow_phase);
+                self.handle_goal(&narrow_phase);
+                self.send_telemetrics();
+            }
+
+            thread::sleep(tick_duration - accumulator);
+        }
+    }
 
-            self.send_telemetrics();
+    fn handle_commands(&mut self) {
+        let commands: Vec<NetworkCommand> = {
+            let (_, receiver) = &self.channels.get("physics").unwrap();
+            self.metrics.record_depth(receiver.len());
+            receiver.try_iter().take(COMMAND_BUDGET).collect()
+        };
+
+        // Inputs are state, an input replaced later in the same tick never moves anybody
+        let received = commands.len();
+        let mut updated: HashSet<PlayerId> = HashSet::new();
+        let mut processed = received;
+
+        for command in commands {
+            if let NetworkCommand::UpdateInput { id, .. } = &command {
+                if !updated.insert(*id) {
+                    processed -= 1;
+                }
+            }
 
-            thread::sleep(delay);
+            self.handle_command(command);
         }
+
+        self.metrics.record_processed(received, processed);
+        self.metrics.report();
     }
 
-    fn handle_command(&mut self, narrow_phase: &NarrowPhase) {
+    // Players send their input every frame, only the latest one is used to move them in a tick
+    fn apply_inputs(&mut self, narrow_phase: &NarrowPhase, dt: f32) {
         #[derive(Debug)]
         struct BallCollision {
             direction: Vector<f32>,
@@ -175,221 +867,519 @@ impl PhysicsEngine {
                 }
             }
 
-            return None
+            None
         }
 
-
-        let (sender, _) = &self.channels.get("network").unwrap().clone();
-        let (_, receiver) = &self.channels.get("physics").unwrap();
-        match receiver.try_recv() {
-            Ok(command) => {
-                match command.kind {
-                    CommandKind::AddPlayer => {
-                        let data = json!(command.data);
-                        let name = String::from(data["name"].as_str().unwrap());
-                        let nickname = String::from(data["nickname"].as_str().unwrap());
-                        
-                        let rad = 15.0;
-                        let translation = vector![128.0, 301.0];
-                        let ball_body = RigidBodyBuilder::new_kinematic_velocity_based()
-                            .translation(translation)
-                            .build();
-                        let collider = ColliderBuilder::ball(rad)
-                            .active_events(ActiveEvents::CONTACT_EVENTS | ActiveEvents::INTERSECTION_EVENTS)
-                            .sensor(true)
-                            .active_collision_types(ActiveCollisionTypes::default() | ActiveCollisionTypes::KINEMATIC_STATIC)
-                            .build();
-
-                        self.insert_body(name.clone(), ball_body, collider);
-
-                        sender.send(NetworkCommand {
-                            kind: CommandKind::AddPlayerAck,
-                            data: json!({
-                                "name": name,
-                                "nickname": nickname,
-                                "translation": { "x": translation.x, "y": translation.y },
-                            })
-                        }).unwrap();
-                    },
-                    CommandKind::ChangePlayerTeam => {
-                        let data = json!(command.data);
-                        let name = data["name"].as_str().unwrap();
-                        let team = data["team"].as_str().unwrap();
-
-                        sender.send(NetworkCommand {
-                            kind: CommandKind::ChangePlayerTeamAck,
-                            data: json!({
-                                "name": name,
-                                "team": team,
-                            })
-                        }).unwrap();
-                    },
-                    CommandKind::MovePlayer | CommandKind::MoveEnemy => {
-                        let data = json!(&command.data);
-                        let name = data.get("name").unwrap();
-                        let velocity = data.get("velocity").unwrap().as_object().unwrap();
-
-                        if let Some(rigid_body_handle) = self.handles.get(name.as_str().unwrap()) {
-                            let (x, y) = (velocity["x"].as_f64().unwrap(), velocity["y"].as_f64().unwrap());
-                            let linvel = vector![x as f32, y as f32];
-    
-                            let rigid_body = self.bodies.get_mut(*rigid_body_handle).unwrap();
-                            rigid_body.set_linvel(linvel, true);
-                            
-                            if let Some(ball_collision) = handle_ball_collision(narrow_phase, &self.bodies, &self.handles, &self.colliders, &rigid_body_handle) {
-                                let power = 100.0;
-                                let ball_rigid_body = self.bodies.get_mut(ball_collision.rigid_body_handle).unwrap();
-                                ball_rigid_body.apply_impulse(-ball_collision.direction * power, true);
-                            }
-                        }
-                    },
-                    CommandKind::KickBall => {
-                        let data = json!(&command.data);
-                        let name = data.get("name").unwrap();
-
-                        if let Some(rigid_body_handle) = self.handles.get(name.as_str().unwrap()) {
-                            let player_rigid_body = self.bodies.get(*rigid_body_handle).unwrap();
-                            let player_translation = player_rigid_body.translation().clone();
-
-                            let ball_rigid_body_handle = self.handles.get("ball").unwrap();
-                            let ball_rigid_body = self.bodies.get_mut(*ball_rigid_body_handle).unwrap();
-                            let ball_translation = ball_rigid_body.translation();
-
-                            let power = 500.0;
-                            let direction = player_translation - ball_translation;
-                            let distance = ((ball_translation.x - player_translation.x).powi(2) + (ball_translation.y - player_translation.y).powi(2)).sqrt();
-                            
-                            if distance < 30.0 {
-                                ball_rigid_body.apply_impulse(-direction * power, true);
-                            }
-                        }
-                    },
-                    CommandKind::DisconnectPlayer => {
-                        let data = json!(&command.data);
-                        let name = data.get("name").unwrap().as_str().unwrap();
-
-                        if let Ok(_) = self.remove_body(name) {
-                            sender.send(NetworkCommand {
-                                kind: CommandKind::DisconnectPlayerAck,
-                                data: json!({
-                                    "name": name
-                                })
-                            }).unwrap();
-                        }
-                    },
-                    _ => trace!("Unknown command: {:?}", command.kind)
-                }
-            },
-            Err(e) => {
-                if !e.is_empty() {
-                    error!("Something went wrong when receiving, error: {:?}", e)
+        let kicks: Vec<PlayerId> = self.pending_kicks.drain().collect();
+        let players: Vec<(PlayerId, RigidBodyHandle)> = self.players.iter()
+            .map(|(id, rigid_body_handle)| (*id, *rigid_body_handle))
+            .collect();
+
+        for (id, rigid_body_handle) in players {
+            let velocity = if self.match_state.phase.allows_movement() {
+                let input = self.player_inputs.get(&id).copied().unwrap_or_default();
+                let velocity = self.player_velocities.get(&id).copied().unwrap_or_else(Vector::zeros);
+                self.movement.next_velocity(velocity, &input, dt)
+            } else {
+                Vector::zeros()
+            };
+
+            let rigid_body = self.bodies.get_mut(rigid_body_handle).unwrap();
+            rigid_body.set_linvel(velocity, true);
+            self.player_velocities.insert(id, velocity);
+
+            if velocity.norm() > 0.0 {
+                if let Some(ball_collision) = handle_ball_collision(narrow_phase, &self.bodies, &self.handles, &self.colliders, &rigid_body_handle) {
+                    let power = self.settings.push_power;
+                    let ball_rigid_body = self.bodies.get_mut(ball_collision.rigid_body_handle).unwrap();
+                    ball_rigid_body.apply_impulse(-ball_collision.direction * power, true);
+                    self.kickoff_pending = false;
                 }
             }
-        }
-    }
-
-    fn send_telemetrics(&mut self) {
-        let (sender, _) = &self.channels.get("network").unwrap();
-        let mut data: HashMap<String, Value> = HashMap::new();
 
-        for (name, handle) in self.handles.iter() {
-            let rigid_body = self.bodies[*handle].clone();
-            let translation = rigid_body.translation();
-
-            data.insert(String::from(name), json!({
-                "translation": { "x": translation.x, "y": translation.y },
-            }));
+            if kicks.contains(&id) && self.match_state.phase.allows_kick() {
+                self.kick_ball(rigid_body_handle);
+            }
         }
+    }
 
-        let telemetrics = json!(data);
-        let command_telemetrics = NetworkCommand {
-            kind: CommandKind::Telemetrics,
-            data: telemetrics
-        };
+    fn kick_ball(&mut self, rigid_body_handle: RigidBodyHandle) {
+        let player_rigid_body = self.bodies.get(rigid_body_handle).unwrap();
+        let player_translation = *player_rigid_body.translation();
 
-        sender.send(command_telemetrics).unwrap();
-    }
-}
+        let ball_rigid_body_handle = self.handles.get("ball").unwrap();
+        let ball_rigid_body = self.bodies.get_mut(*ball_rigid_body_handle).unwrap();
+        let ball_translation = ball_rigid_body.translation();
 
-#[derive(Debug, Clone, Serialize, PartialEq)]
-enum TeamKind {
-    RedTeam,
-    SpecTeam,
-    BlueTeam
-}
+        let power = self.settings.kick_power;
+        let direction = player_translation - ball_translation;
+        let distance = ((ball_translation.x - player_translation.x).powi(2) + (ball_translation.y - player_translation.y).powi(2)).sqrt();
 
-impl FromStr for TeamKind {
-    type Err = ();
-    fn from_str(input: &str) -> Result<TeamKind, Self::Err> {
-        match input {
-            "RedTeam"  => Ok(TeamKind::RedTeam),
-            "SpecTeam"  => Ok(TeamKind::SpecTeam),
-            "BlueTeam"  => Ok(TeamKind::BlueTeam),
-            _      => Err(()),
+        if distance < self.settings.kick_range {
+            ball_rigid_body.apply_impulse(-direction * power, true);
+            self.kickoff_pending = false;
         }
     }
-}
 
-#[derive(Debug, Clone)]
-struct Client {
-    ip_address: String,
-    nickname: String,
-    team: TeamKind
-}
+    fn handle_command(&mut self, command: NetworkCommand) {
+        let (sender, _) = &self.channels.get("network").unwrap().clone();
 
-impl Client {
-    fn assign_team(&mut self, team: TeamKind) {
-        self.team = team;
-    }
-}
+        match command {
+            NetworkCommand::AddPlayer { id, nickname } => {
+                // Every player joins as a spectator, the body is added once a team is picked
+                self.teams.insert(id, TeamKind::SpecTeam);
 
-#[derive(Debug)]
-enum CommandKind {
-    AddPlayer,
-    AddPlayerAck,
+                let players = self.player_positions();
+                let translation = players[&id];
 
-    ChangePlayerTeam,
-    ChangePlayerTeamAck,
+                sender.send(NetworkCommand::AddPlayerAck {
+                    team: self.teams[&id].clone(),
+                    id,
+                    nickname,
+                    translation,
+                    players,
+                }).unwrap();
+            },
+            NetworkCommand::ChangePlayerTeam { id, team } => {
+                let teammates = self.teams.iter()
+                    .filter(|(player_id, player_team)| **player_team == team && **player_id != id)
+                    .count();
+                let translation = self.spawn_position(&team, teammates);
+
+                // The body is rebuilt so the collider picks up the groups of the new team,
+                // spectators only watch and must not be able to push the ball
+                let _ = self.remove_player_body(id);
+                self.player_velocities.remove(&id);
+                if team != TeamKind::SpecTeam {
+                    self.add_player_body(id, translation, &team);
+                }
 
-    DisconnectPlayer,
-    DisconnectPlayerAck,
+                self.teams.insert(id, team.clone());
 
-    MovePlayer,
-    MoveEnemy,
+                sender.send(NetworkCommand::ChangePlayerTeamAck { id, team }).unwrap();
+            },
+            NetworkCommand::UpdateInput { id, input, sequence } => {
+                // The input counts as processed even when the phase ignores it, so clients stop replaying it
+                if let Some(sequence) = sequence {
+                    self.input_sequences.insert(id, sequence);
+                }
+
+                let previous = self.player_inputs.insert(id, input).unwrap_or_default();
+                if input.kick && !previous.kick {
+                    self.pending_kicks.insert(id);
+                }
+            },
+            NetworkCommand::ParkPlayer { id } => {
+                // The body stays where it is until the client comes back or the grace runs out
+                self.player_inputs.remove(&id);
+                self.pending_kicks.remove(&id);
+            },
+            NetworkCommand::ResumePlayer { id } => {
+                // Keys held before the connection dropped are not held anymore
+                if self.teams.contains_key(&id) {
+                    self.player_inputs.remove(&id);
+                    self.pending_kicks.remove(&id);
+                    self.input_sequences.remove(&id);
+
+                    sender.send(NetworkCommand::ResumePlayerAck {
+                        id,
+                        players: self.player_positions(),
+                    }).unwrap();
+                }
+            },
+            NetworkCommand::UpdateSettings { settings, movement, changes } => {
+                self.settings = settings;
+                self.movement = movement;
+                self.apply_settings();
+
+                sender.send(NetworkCommand::SettingsChanged { changes }).unwrap();
+            },
+            NetworkCommand::StartMatch { time_limit, score_limit } => {
+                let mut settings = MatchSettings::default();
+
+                if let Some(time_limit) = time_limit {
+                    settings.time_limit = time_limit;
+                }
+                if let Some(score_limit) = score_limit {
+                    settings.score_limit = score_limit;
+                }
+
+                if self.match_state.start(settings) {
+                    self.handle_phase_change();
+                }
+            },
+            NetworkCommand::StopMatch => {
+                if self.match_state.stop() {
+                    self.handle_phase_change();
+                }
+            },
+            NetworkCommand::Shutdown => self.running = false,
+            NetworkCommand::DisconnectPlayer { id } => {
+                if self.teams.remove(&id).is_some() {
+                    // Spectators do not have a body
+                    let _ = self.remove_player_body(id);
+                    self.player_inputs.remove(&id);
+                    self.player_velocities.remove(&id);
+                    self.pending_kicks.remove(&id);
+                    self.input_sequences.remove(&id);
+
+                    sender.send(NetworkCommand::DisconnectPlayerAck { id }).unwrap();
+                }
+            },
+            _ => trace!("Unknown command: {:?}", command)
+        }
+    }
+
+    fn body_telemetrics(&self, rigid_body_handle: RigidBodyHandle, linvel: Option<Vector<f32>>, input_sequence: Option<u32>) -> BodyTelemetrics {
+        let rigid_body = &self.bodies[rigid_body_handle];
+        let team = rigid_body.colliders().first()
+            .and_then(|collider_handle| TeamKind::from_user_data(self.colliders[*collider_handle].user_data));
+
+        BodyTelemetrics {
+            translation: *rigid_body.translation(),
+            linvel: linvel.unwrap_or(*rigid_body.linvel()),
+            rotation: rigid_body.rotation().angle(),
+            angvel: rigid_body.angvel(),
+            input_sequence,
+            team,
+        }
+    }
 
-    KickBall,
+    fn send_telemetrics(&mut self) {
+        let (sender, _) = &self.channels.get("network").unwrap();
 
-    Telemetrics,
+        // Only the ball and the players move, the rest of the field is known to clients up front
+        let ball = match self.handles.get("ball") {
+            Some(ball_rigid_body_handle) => self.body_telemetrics(*ball_rigid_body_handle, None, None),
+            None => return,
+        };
+
+        let players: HashMap<PlayerId, BodyTelemetrics> = self.players.iter()
+            .map(|(id, rigid_body_handle)| (*id, self.body_telemetrics(
+                *rigid_body_handle,
+                self.player_velocities.get(id).copied(),
+                self.input_sequences.get(id).copied(),
+            )))
+            .collect();
+
+        sender.send(NetworkCommand::Telemetrics {
+            tick: self.tick,
+            ball,
+            players,
+        }).unwrap();
+    }
 }
 
-#[derive(Debug)]
-struct NetworkCommand {
-    kind: CommandKind,
-    data: Value,
+// What the simulation needs to know about a team on top of what clients see
+trait TeamPhysics {
+    fn user_data(&self) -> u128;
+    fn from_user_data(user_data: u128) -> Option<TeamKind>;
+    fn collision_group(&self) -> u32;
+    fn side(&self) -> f32;
+}
+
+impl TeamPhysics for TeamKind {
+    // Stored in the user data of the player collider, zero is left for colliders without a team
+    fn user_data(&self) -> u128 {
+        match self {
+            TeamKind::RedTeam => 1,
+            TeamKind::SpecTeam => 2,
+            TeamKind::BlueTeam => 3,
+        }
+    }
+
+    fn from_user_data(user_data: u128) -> Option<TeamKind> {
+        match user_data {
+            1 => Some(TeamKind::RedTeam),
+            2 => Some(TeamKind::SpecTeam),
+            3 => Some(TeamKind::BlueTeam),
+            _ => None,
+        }
+    }
+
+    fn collision_group(&self) -> u32 {
+        match self {
+            TeamKind::RedTeam => 0b001,
+            TeamKind::BlueTeam => 0b010,
+            TeamKind::SpecTeam => 0,
+        }
+    }
+
+    // Red team defends the left goal, blue team the right one
+    fn side(&self) -> f32 {
+        match self {
+            TeamKind::RedTeam => -1.0,
+            TeamKind::BlueTeam => 1.0,
+            TeamKind::SpecTeam => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FailedJoins {
+    count: u32,
+    last: Instant,
+}
+
+// Wrong passwords and invite codes, counted per address rather than per client
+// because handshakes are rejected before there is a client
+#[derive(Debug, Default)]
+struct JoinThrottle {
+    failed_joins: HashMap<IpAddr, FailedJoins>,
+}
+
+impl JoinThrottle {
+    fn is_blocked(&self, ip: IpAddr, now: Instant) -> bool {
+        self.failed_joins.get(&ip).is_some_and(|failed| {
+            failed.count >= FAILED_JOIN_LIMIT && now.saturating_duration_since(failed.last) < FAILED_JOIN_BACKOFF
+        })
+    }
+
+    // Returns the number of failures of the address so far
+    fn record_failure(&mut self, ip: IpAddr, now: Instant) -> u32 {
+        let failed = self.failed_joins.entry(ip).or_insert(FailedJoins { count: 0, last: now });
+        failed.count += 1;
+        failed.last = now;
+        failed.count
+    }
+
+    // Addresses that stayed quiet for the backoff start over
+    fn forget(&mut self, now: Instant) {
+        self.failed_joins.retain(|_, failed| now.saturating_duration_since(failed.last) < FAILED_JOIN_BACKOFF);
+    }
 }
 
 #[derive(Debug, Clone)]
-struct Network {
+struct Client {
+    id: PlayerId,
+    address: SocketAddr,
+    nickname: String,
+    team: TeamKind,
+    codec: Codec,
+    acked_tick: Option<u64>,
+    input_sequence: Option<u32>,
+    malformed_packets: u32,
+    session_token: String,
+    // Set when the connection timed out, the player is kept until the reconnect grace runs out
+    disconnected_at: Option<Instant>,
+}
+
+impl Client {
+    fn assign_team(&mut self, team: TeamKind) {
+        self.team = team;
+    }
+}
+
+#[derive(Debug)]
+struct BodyTelemetrics {
+    translation: Vector<f32>,
+    linvel: Vector<f32>,
+    rotation: f32,
+    angvel: f32,
+    input_sequence: Option<u32>,
+    team: Option<TeamKind>,
+}
+
+impl BodyTelemetrics {
+    fn snapshot(&self, precision: &Precision) -> BodySnapshot {
+        BodySnapshot::new(
+            (self.translation.x, self.translation.y),
+            (self.linvel.x, self.linvel.y),
+            self.rotation,
+            self.angvel,
+            precision,
+        )
+    }
+}
+
+#[derive(Debug)]
+enum NetworkCommand {
+    AddPlayer { id: PlayerId, nickname: String },
+    AddPlayerAck { id: PlayerId, nickname: String, team: TeamKind, translation: Vector<f32>, players: HashMap<PlayerId, Vector<f32>> },
+
+    ChangePlayerTeam { id: PlayerId, team: TeamKind },
+    ChangePlayerTeamAck { id: PlayerId, team: TeamKind },
+
+    DisconnectPlayer { id: PlayerId },
+    DisconnectPlayerAck { id: PlayerId },
+
+    ParkPlayer { id: PlayerId },
+    ResumePlayer { id: PlayerId },
+    ResumePlayerAck { id: PlayerId, players: HashMap<PlayerId, Vector<f32>> },
+
+    UpdateInput { id: PlayerId, input: PlayerInput, sequence: Option<u32> },
+
+    UpdateSettings { settings: PhysicsSettings, movement: MovementSettings, changes: Vec<SettingChange> },
+    SettingsChanged { changes: Vec<SettingChange> },
+
+    StartMatch { time_limit: Option<Duration>, score_limit: Option<u32> },
+    StopMatch,
+    MatchPhaseChanged { phase: MatchPhase, half: u8, score: Score, clock: Duration, countdown: Duration },
+
+    Goal { team: TeamKind, score: Score },
+
+    Telemetrics { tick: u64, ball: BodyTelemetrics, players: HashMap<PlayerId, BodyTelemetrics> },
+
+    Shutdown,
+}
+
+// One match with its own simulation thread, the players in it and the snapshots sent to them
+struct Room {
+    id: RoomId,
+    name: String,
+    permanent: bool,
+    password: Option<String>,
+    // Set for invite-only rooms, which are left out of the room list
+    invite_code: Option<String>,
+    // Only the host starts and stops the match, the first player in gets it and passes it on
+    // when leaving or timing out, nobody hosts while every player is parked
+    host: Option<PlayerId>,
     channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)>,
+    map: String,
     clients: Vec<Client>,
+    phase: MatchPhase,
+    match_phase: Option<Message>,
+    snapshots: SnapshotHistory,
+    precision: Precision,
+    telemetry_interval: Duration,
+    last_telemetrics: Instant,
+    command_metrics: QueueMetrics,
 }
 
-impl Network {
+impl Room {
+    fn open(id: RoomId, name: String, permanent: bool, config: &ServerConfig) -> Room {
+        let mut channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)> = HashMap::new();
+
+        channels.insert(String::from("network"), unbounded());
+        channels.insert(String::from("physics"), unbounded());
+
+        let mut physics_engine = PhysicsEngine {
+            channels: channels.clone(),
+            handles: HashMap::new(),
+            players: HashMap::new(),
+            bodies: RigidBodySet::new(),
+            colliders: ColliderSet::new(),
+            joints: JointSet::new(),
+            islands: IslandManager::new(),
+            teams: HashMap::new(),
+            player_inputs: HashMap::new(),
+            player_velocities: HashMap::new(),
+            pending_kicks: HashSet::new(),
+            input_sequences: HashMap::new(),
+            settings: config.physics.clone(),
+            movement: config.movement,
+            field: Field::default(),
+            goals: Vec::new(),
+            match_state: MatchState::new(MatchSettings::default()),
+            kickoff_pending: false,
+            tick: 0,
+            running: true,
+            metrics: QueueMetrics::new(format!("room {} physics", id)),
+        };
+
+        thread::spawn(move || physics_engine.start_simulation());
+
+        Room {
+            id,
+            name,
+            permanent,
+            password: None,
+            invite_code: None,
+            host: None,
+            channels,
+            map: String::from(DEFAULT_MAP),
+            clients: Vec::new(),
+            phase: MatchPhase::Lobby,
+            match_phase: None,
+            snapshots: SnapshotHistory::new(SNAPSHOT_HISTORY),
+            precision: config.precision,
+            telemetry_interval: config.network.telemetry_interval(),
+            last_telemetrics: Instant::now(),
+            command_metrics: QueueMetrics::new(format!("room {} network", id)),
+        }
+    }
+
+    fn close(&mut self) {
+        self.send_command(NetworkCommand::Shutdown).unwrap();
+    }
+
+    fn info(&self) -> RoomInfo {
+        let players = TeamKind::ALL.iter()
+            .map(|team| (team.clone(), self.clients.iter().filter(|client| client.team == *team).count() as u32))
+            .collect();
+
+        RoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            players,
+            has_password: self.password.is_some(),
+            match_phase: self.phase,
+            map: self.map.clone(),
+        }
+    }
+
+    // Invite-only rooms cannot be joined by their id, the code alone is enough to find them
+    fn admit(&self, access: &RoomAccess) -> Result<(), JoinRejectReason> {
+        if let Some(invite_code) = &self.invite_code {
+            match access.invite_code.as_deref().map(normalize_invite_code) {
+                Some(code) if code == *invite_code => {},
+                Some(_) => return Err(JoinRejectReason::InvalidInviteCode),
+                None => return Err(JoinRejectReason::InviteOnly),
+            }
+        }
+
+        if let Some(password) = &self.password {
+            if !access.password.as_deref().is_some_and(|given| passwords_match(password, given)) {
+                return Err(JoinRejectReason::WrongPassword);
+            }
+        }
+
+        Ok(())
+    }
+
     fn add_client(&mut self, client: Client) {
         self.clients.push(client);
+        self.update_host();
+    }
+
+    // Only a connected player can host, a host that came back from a timeout does not take the role back
+    fn update_host(&mut self) {
+        let connected = |client: &&Client| client.disconnected_at.is_none();
+
+        if self.clients.iter().filter(connected).any(|client| Some(client.id) == self.host) {
+            return;
+        }
+
+        self.host = self.clients.iter().find(connected).map(|client| client.id);
+
+        if let Some(host) = self.host {
+            info!("Player {} is the host of room {} now", host, self.id);
+        }
     }
 
     fn get_clients(&mut self) -> &mut Vec<Client> {
         &mut self.clients
     }
 
-    fn get_client(&mut self, ip_address: &String) -> Option<&mut Client> {
-        self.clients.iter_mut().find(|client| client.ip_address.eq(ip_address))
+    fn get_client(&mut self, address: &SocketAddr) -> Option<&mut Client> {
+        self.clients.iter_mut().find(|client| client.address.eq(address))
     }
 
-    fn delete_client(&mut self, client: &Client) {
-        let index = self.clients.iter().position(|c| c.ip_address.eq(&client.ip_address)).unwrap();
-        self.clients.remove(index);
+    fn get_client_by_id(&mut self, id: PlayerId) -> Option<&mut Client> {
+        self.clients.iter_mut().find(|client| client.id == id)
+    }
+
+    // The player leaves the simulation, the client itself is handed back to the caller
+    fn remove_client(&mut self, address: &SocketAddr) -> Option<Client> {
+        let index = self.clients.iter().position(|client| client.address == *address)?;
+        let client = self.clients.remove(index);
+        self.update_host();
+
+        self.send_command(NetworkCommand::DisconnectPlayer {
+            id: client.id,
+        }).unwrap();
+
+        Some(client)
     }
 
     fn send_command(&mut self, command: NetworkCommand) -> Result<(), SendError<NetworkCommand>> {
@@ -397,392 +1387,941 @@ impl Network {
         sender.send(command)
     }
 
-    fn handle_message(&mut self, msg: &Message, ip_address: &String) {
+    // Actions of players that already are in this room
+    fn handle_message(&mut self, msg: &Message, address: &SocketAddr, action: Action, payload: &Value, packet_sender: &Sender<Packet>) -> Result<(), DecodeError> {
+        let client = match self.get_client(address) {
+            Some(client) => client.clone(),
+            None => return Ok(()),
+        };
+
+        match action {
+            Action::ChangePlayerTeam => {
+                let team = payload.get("team").ok_or(DecodeError::MissingField("team"))?;
+                let team = TeamKind::deserialize(team).map_err(|_| DecodeError::InvalidField("team"))?;
+
+                if let Some(client) = self.get_client(address) {
+                    client.assign_team(team.clone());
+                    let id = client.id;
+
+                    self.send_command(NetworkCommand::ChangePlayerTeam {
+                        id,
+                        team,
+                    }).unwrap();
+                }
+            },
+            Action::PlayerInput => {
+                let input: PlayerInput = msg.decode_payload()?;
+
+                let sequence = Room::input_sequence(payload)?;
+                if !self.accept_input_sequence(address, sequence) {
+                    return Ok(());
+                }
+
+                self.send_command(NetworkCommand::UpdateInput {
+                    id: client.id,
+                    input,
+                    sequence,
+                }).unwrap();
+            },
+            Action::SnapshotAck => {
+                let tick = payload["tick"].as_u64().ok_or(DecodeError::MissingField("tick"))?;
+
+                // Acks can arrive out of order, only the newest one is a useful baseline
+                if let Some(client) = self.get_client(address) {
+                    if client.acked_tick.is_none_or(|acked_tick| tick > acked_tick) {
+                        client.acked_tick = Some(tick);
+                    }
+                }
+            },
+            Action::StartMatch | Action::StopMatch if self.host != Some(client.id) => {
+                warn!("Player {} is not the host of room {}, it cannot {:?}", client.id, self.id, action);
+                self.reject_match_control(packet_sender, &client);
+            },
+            Action::StartMatch => {
+                self.send_command(NetworkCommand::StartMatch {
+                    time_limit: payload["time_limit"].as_u64().map(Duration::from_secs),
+                    score_limit: payload["score_limit"].as_u64().map(|score_limit| score_limit as u32),
+                }).unwrap();
+            },
+            Action::StopMatch => {
+                self.send_command(NetworkCommand::StopMatch).unwrap();
+            },
+            _ => trace!("Unknown action: {:?}", payload)
+        }
+
+        Ok(())
+    }
+
+    fn reject_match_control(&self, packet_sender: &Sender<Packet>, client: &Client) {
+        let match_control_rejected_message = Message {
+            kind: MessageKind::Data,
+            payload: json!({
+                "action": Action::MatchControlRejected,
+                "reason": String::from("NotHost"),
+                "host": self.host
+            }).to_string(),
+        };
+
+        let data_message = client.codec.encode_message(&match_control_rejected_message);
+        let packet = Delivery::of(Action::MatchControlRejected).packet(client.address, data_message);
+        packet_sender.send(packet).unwrap();
+    }
+
+    fn input_sequence(payload: &Value) -> Result<Option<u32>, DecodeError> {
+        match payload.get("sequence") {
+            None => Ok(None),
+            Some(sequence) => sequence.as_u64()
+                .and_then(|sequence| u32::try_from(sequence).ok())
+                .map(Some)
+                .ok_or(DecodeError::InvalidField("sequence")),
+        }
+    }
+
+    // Inputs are sent unreliably, anything not newer than the last accepted one is a duplicate or arrived out of order
+    fn accept_input_sequence(&mut self, address: &SocketAddr, sequence: Option<u32>) -> bool {
+        let (client, sequence) = match (self.get_client(address), sequence) {
+            (Some(client), Some(sequence)) => (client, sequence),
+            _ => return true,
+        };
+
+        if client.input_sequence.is_some_and(|last_sequence| !is_newer_sequence(sequence, last_sequence)) {
+            debug!("Dropped input {} from player {}, last accepted: {:?}", sequence, client.id, client.input_sequence);
+            return false;
+        }
+
+        client.input_sequence = Some(sequence);
+        true
+    }
+
+    // Roster and match phase a player needs after joining
+    fn send_world_state(&self, packet_sender: &Sender<Packet>, player: &Client, roster: &[Client], players: &HashMap<PlayerId, Vector<f32>>) {
+        for client in roster {
+            if let Some(player_position) = players.get(&client.id) {
+                let existing_player_message = Message {
+                    kind: MessageKind::Data,
+                    payload: json!({
+                        "action": Action::PlayerAddAck,
+                        "id": client.id,
+                        "nickname": client.nickname,
+                        "position": { "x": player_position.x, "y": player_position.y },
+                        "team": client.team
+                    }).to_string(),
+                };
+
+                let data_message = player.codec.encode_message(&existing_player_message);
+                let packet = Delivery::of(Action::PlayerAddAck).packet(player.address, data_message);
+                packet_sender.send(packet).unwrap();
+            }
+        }
+
+        if let Some(match_phase_message) = &self.match_phase {
+            let data_message = player.codec.encode_message(match_phase_message);
+            let packet = Delivery::of(Action::MatchPhase).packet(player.address, data_message);
+            packet_sender.send(packet).unwrap();
+        }
+    }
+
+    fn handle_telemetrics(&mut self, packet_sender: &Sender<Packet>) {
+        let commands: Vec<NetworkCommand> = {
+            let (_, receiver) = &self.channels.get("network").unwrap();
+            self.command_metrics.record_depth(receiver.len());
+            receiver.try_iter().take(COMMAND_BUDGET).collect()
+        };
+
+        // Older telemetrics are stale as soon as a newer one is queued
+        let latest_telemetrics = commands.iter().rposition(|command| matches!(command, NetworkCommand::Telemetrics { .. }));
+
+        let received = commands.len();
+        let mut processed = 0;
+
+        for (index, command) in commands.into_iter().enumerate() {
+            if matches!(command, NetworkCommand::Telemetrics { .. }) && Some(index) != latest_telemetrics {
+                continue;
+            }
+
+            self.handle_network_command(command, packet_sender);
+            processed += 1;
+        }
+
+        self.command_metrics.record_processed(received, processed);
+        self.command_metrics.report();
+    }
+
+    fn handle_network_command(&mut self, command: NetworkCommand, packet_sender: &Sender<Packet>) {
+        match command {
+            NetworkCommand::AddPlayerAck { id: new_player_id, nickname: new_player_nickname, team: new_player_team, translation, players } => {
+                let (x, y) = (translation.x, translation.y);
+
+                let clients = self.get_clients().clone();
+                let new_player = match self.get_client_by_id(new_player_id) {
+                    Some(new_player) => new_player.clone(),
+                    None => return,
+                };
+
+                for client in &clients {
+                    let add_player_ack_message = Message {
+                        kind: MessageKind::Data,
+                        payload: json!({
+                            "action": Action::PlayerAddAck,
+                            "id": new_player_id,
+                            "nickname": new_player_nickname,
+                            "position": { "x": x, "y": y },
+                            "team": new_player_team
+                        }).to_string(),
+                    };
+
+                    let data_message = client.codec.encode_message(&add_player_ack_message);
+                    let packet = Delivery::of(Action::PlayerAddAck).packet(client.address, data_message);
+                    packet_sender.send(packet).unwrap();
+                }
+
+                let existing_players: Vec<Client> = clients.into_iter()
+                    .filter(|client| client.id != new_player_id)
+                    .collect();
+                self.send_world_state(packet_sender, &new_player, &existing_players, &players);
+            },
+            NetworkCommand::ResumePlayerAck { id, players } => {
+                let player = match self.get_client_by_id(id) {
+                    Some(player) => player.clone(),
+                    None => return,
+                };
+
+                // The client may have restarted, so it gets everyone including itself
+                let clients = self.get_clients().clone();
+                self.send_world_state(packet_sender, &player, &clients, &players);
+            },
+            NetworkCommand::ChangePlayerTeamAck { id: player_id, team } => {
+                let clients = self.get_clients().clone();
+                let player = match self.get_client_by_id(player_id) {
+                    Some(player) => player.clone(),
+                    None => return,
+                };
+
+                for client in &clients {
+                    let add_player_ack_message = Message {
+                        kind: MessageKind::Data,
+                        payload: json!({
+                            "action": Action::ChangePlayerTeamAck,
+                            "id": player_id,
+                            "team": team,
+                        }).to_string(),
+                    };
+
+                    let data_message = client.codec.encode_message(&add_player_ack_message);
+                    let packet = Delivery::of(Action::ChangePlayerTeamAck).packet(client.address, data_message);
+                    packet_sender.send(packet).unwrap();
+                }
+
+                for client in &clients {
+                    if client.id != player_id {
+                        let existing_player_message = Message {
+                            kind: MessageKind::Data,
+                            payload: json!({
+                                "action": Action::ChangePlayerTeamAck,
+                                "id": client.id,
+                                "team": client.team
+                            }).to_string(),
+                        };
+
+                        let data_message = player.codec.encode_message(&existing_player_message);
+                        let packet = Delivery::of(Action::ChangePlayerTeamAck).packet(player.address, data_message);
+                        packet_sender.send(packet).unwrap();
+                    }
+                }
+            },
+            NetworkCommand::Telemetrics { tick, ball, players } => {
+                if self.last_telemetrics.elapsed() < self.telemetry_interval {
+                    return;
+                }
+                self.last_telemetrics = Instant::now();
+
+                let ball = ball.snapshot(&self.precision);
+
+                let mut players: Vec<PlayerSnapshot> = players.iter()
+                    .map(|(id, body)| PlayerSnapshot {
+                        id: *id,
+                        team: body.team.clone(),
+                        input_sequence: body.input_sequence,
+                        body: body.snapshot(&self.precision),
+                    })
+                    .collect();
+                players.sort_by_key(|player| player.id);
+
+                let snapshot = WorldSnapshot { tick, precision: self.precision, ball, players };
+
+                // Spectators have no body of their own, they get the same snapshot as everyone else.
+                // Clients without an acknowledged snapshot still in the history get a full one.
+                for client in self.clients.iter() {
+                    let data_message = match self.snapshots.baseline(client.acked_tick, &snapshot) {
+                        Some(baseline) => client.codec.encode_delta(&snapshot.delta(baseline)),
+                        None => client.codec.encode_snapshot(&snapshot),
+                    };
+                    let packet = Delivery::of(Action::WorldSnapshot).packet(client.address, data_message);
+                    packet_sender.send(packet).unwrap();
+                }
+
+                self.snapshots.push(snapshot);
+            },
+            NetworkCommand::MatchPhaseChanged { phase, half, score, clock, countdown } => {
+                let match_phase_message = Message {
+                    kind: MessageKind::Data,
+                    payload: json!({
+                        "action": Action::MatchPhase,
+                        "phase": phase,
+                        "half": half,
+                        "score": score,
+                        "clock": clock.as_secs_f32(),
+                        "countdown": countdown.as_secs_f32()
+                    }).to_string(),
+                };
+
+                let clients = self.get_clients().clone();
+
+                for client in &clients {
+                    let data_message = client.codec.encode_message(&match_phase_message);
+                    let packet = Delivery::of(Action::MatchPhase).packet(client.address, data_message);
+                    packet_sender.send(packet).unwrap();
+                }
+
+                self.phase = phase;
+                self.match_phase = Some(match_phase_message);
+            },
+            NetworkCommand::Goal { team, score } => {
+                let goal_message = Message {
+                    kind: MessageKind::Data,
+                    payload: json!({
+                        "action": Action::Goal,
+                        "team": team,
+                        "score": score
+                    }).to_string(),
+                };
+
+                let clients = self.get_clients().clone();
+
+                for client in &clients {
+                    let data_message = client.codec.encode_message(&goal_message);
+                    let packet = Delivery::of(Action::Goal).packet(client.address, data_message);
+                    packet_sender.send(packet).unwrap();
+                }
+            },
+            NetworkCommand::DisconnectPlayerAck { id: player_id } => {
+                let disconnect_player_ack_message = Message {
+                    kind: MessageKind::Data,
+                    payload: json!({
+                        "action": Action::PlayerDisconnectAck,
+                        "id": player_id
+                    }).to_string(),
+                };
+
+                let clients = self.get_clients().clone();
+
+                for client in &clients {
+                    let data_message = client.codec.encode_message(&disconnect_player_ack_message);
+                    let packet = Delivery::of(Action::PlayerDisconnectAck).packet(client.address, data_message);
+                    packet_sender.send(packet).unwrap();
+                }
+            },
+            NetworkCommand::SettingsChanged { changes } => {
+                let settings_changed_message = Message {
+                    kind: MessageKind::Data,
+                    payload: json!({
+                        "action": Action::SettingsChanged,
+                        "changes": changes
+                    }).to_string(),
+                };
+
+                let clients = self.get_clients().clone();
+
+                for client in &clients {
+                    let data_message = client.codec.encode_message(&settings_changed_message);
+                    let packet = Delivery::of(Action::SettingsChanged).packet(client.address, data_message);
+                    packet_sender.send(packet).unwrap();
+                }
+            },
+            _ => trace!("Unknown command: {:?}", command)
+        }
+    }
+}
+
+// Owns the socket and every connected client, players are routed to the room they joined
+struct Network {
+    channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)>,
+    config: ServerConfig,
+    // Connected clients that are not in any room
+    lobby: Vec<Client>,
+    rooms: BTreeMap<RoomId, Room>,
+    join_throttle: JoinThrottle,
+    next_player_id: u32,
+    next_room_id: u32,
+    socket_metrics: QueueMetrics,
+}
+
+impl Network {
+    fn new(channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)>, config: ServerConfig) -> Network {
+        Network {
+            channels,
+            config,
+            lobby: Vec::new(),
+            rooms: BTreeMap::new(),
+            join_throttle: JoinThrottle::default(),
+            next_player_id: 1,
+            next_room_id: 1,
+            socket_metrics: QueueMetrics::new(String::from("socket")),
+        }
+    }
+
+    fn clients(&self) -> impl Iterator<Item = &Client> {
+        self.lobby.iter().chain(self.rooms.values().flat_map(|room| room.clients.iter()))
+    }
+
+    fn get_client(&mut self, address: &SocketAddr) -> Option<&mut Client> {
+        self.lobby.iter_mut()
+            .chain(self.rooms.values_mut().flat_map(|room| room.clients.iter_mut()))
+            .find(|client| client.address.eq(address))
+    }
+
+    fn get_client_by_id(&mut self, id: PlayerId) -> Option<&mut Client> {
+        self.lobby.iter_mut()
+            .chain(self.rooms.values_mut().flat_map(|room| room.clients.iter_mut()))
+            .find(|client| client.id == id)
+    }
+
+    fn get_room(&mut self, address: &SocketAddr) -> Option<&mut Room> {
+        self.rooms.values_mut().find(|room| room.clients.iter().any(|client| client.address == *address))
+    }
+
+    fn next_player_id(&mut self) -> PlayerId {
+        let id = PlayerId(self.next_player_id);
+        self.next_player_id += 1;
+        id
+    }
+
+    fn open_room(&mut self, name: String, permanent: bool, access: RoomAccessSettings) -> RoomId {
+        let id = RoomId(self.next_room_id);
+        self.next_room_id += 1;
+
+        info!("Opened room {} ({})", id, name);
+
+        let mut room = Room::open(id, name, permanent, &self.config);
+        room.password = access.password.filter(|password| !password.is_empty());
+        room.invite_code = if access.invite_only { Some(self.generate_invite_code()) } else { None };
+
+        // Nobody created a permanent room, so whoever runs the server hands out its code
+        if let (true, Some(invite_code)) = (permanent, &room.invite_code) {
+            info!("Room {} ({}) is invite only, its invite code is {}", id, room.name, invite_code);
+        }
+
+        self.rooms.insert(id, room);
+        id
+    }
+
+    // Rooms created by players are closed as soon as the last one leaves
+    fn close_room_if_empty(&mut self, id: RoomId) {
+        if !self.rooms.get(&id).is_some_and(|room| room.clients.is_empty() && !room.permanent) {
+            return;
+        }
+
+        if let Some(mut room) = self.rooms.remove(&id) {
+            info!("Closed room {} ({})", room.id, room.name);
+            room.close();
+        }
+    }
+
+    // Leaving, timing out and misbehaving all end here, so no player is left behind on the field
+    fn disconnect_client(&mut self, address: &SocketAddr) {
+        if let Some(room) = self.get_room(address) {
+            let room_id = room.id;
+            room.remove_client(address);
+            self.close_room_if_empty(room_id);
+        } else {
+            self.lobby.retain(|client| client.address != *address);
+        }
+    }
+
+    // Timed out players that were not resumed within the grace leave for good
+    fn remove_expired_clients(&mut self) {
+        let reconnect_grace = self.config.network.reconnect_grace();
+
+        let expired: Vec<(PlayerId, SocketAddr)> = self.clients()
+            .filter(|client| client.disconnected_at.is_some_and(|disconnected_at| disconnected_at.elapsed() > reconnect_grace))
+            .map(|client| (client.id, client.address))
+            .collect();
+
+        for (id, address) in expired {
+            info!("Player {} did not come back within {:?}", id, reconnect_grace);
+            self.disconnect_client(&address);
+        }
+    }
+
+    fn send_lobby_reply(&self, packet_sender: &Sender<Packet>, client: &Client, reply: &LobbyReply) {
+        let lobby_reply_message = Message {
+            kind: MessageKind::Data,
+            payload: serde_json::to_string(reply).unwrap(),
+        };
+
+        let data_message = client.codec.encode_message(&lobby_reply_message);
+        let packet = Delivery::of(reply.action()).packet(client.address, data_message);
+        packet_sender.send(packet).unwrap();
+    }
+
+    fn handle_lobby_request(&mut self, packet_sender: &Sender<Packet>, client: &Client, request: LobbyRequest) {
+        match request {
+            LobbyRequest::ListRooms => {
+                let rooms = self.rooms.values()
+                    .filter(|room| room.invite_code.is_none())
+                    .map(Room::info)
+                    .collect();
+                self.send_lobby_reply(packet_sender, client, &LobbyReply::RoomList { rooms });
+            },
+            LobbyRequest::CreateRoom { name, password, invite_only } => self.create_room(packet_sender, client, name, password, invite_only),
+            LobbyRequest::JoinRoom(access) => self.join_room(packet_sender, client, &access),
+            LobbyRequest::LeaveRoom => self.leave_room(packet_sender, &client.address),
+        }
+    }
+
+    fn create_room(&mut self, packet_sender: &Sender<Packet>, client: &Client, name: Option<String>, password: Option<String>, invite_only: bool) {
+        if self.rooms.len() >= self.config.rooms.max_rooms {
+            warn!("Player {} cannot create a room, {} rooms are open already", client.id, self.rooms.len());
+
+            self.send_lobby_reply(packet_sender, client, &LobbyReply::JoinRejected {
+                room: None,
+                reason: JoinRejectReason::TooManyRooms,
+            });
+            return;
+        }
+
+        let name = name.as_deref()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| name.chars().take(ROOM_NAME_LENGTH).collect())
+            .unwrap_or_else(|| format!("Room of {}", client.nickname));
+
+        let room_id = self.open_room(name, false, RoomAccessSettings { password, invite_only });
+
+        // The creator gets in without the password it just chose
+        self.enter_room(packet_sender, client, room_id);
+    }
+
+    fn generate_invite_code(&self) -> String {
+        let mut rng = rand::thread_rng();
+
+        loop {
+            let code: String = (0..INVITE_CODE_LENGTH)
+                .map(|_| *INVITE_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+                .collect();
+
+            if self.rooms.values().all(|room| room.invite_code.as_ref() != Some(&code)) {
+                return code;
+            }
+        }
+    }
+
+    fn find_room(&self, access: &RoomAccess) -> Result<RoomId, JoinRejectReason> {
+        let room = match (access.room, access.invite_code.as_deref()) {
+            (Some(room_id), _) => self.rooms.get(&room_id).ok_or(JoinRejectReason::RoomNotFound)?,
+            (None, Some(invite_code)) => {
+                let invite_code = normalize_invite_code(invite_code);
+                self.rooms.values()
+                    .find(|room| room.invite_code.as_ref() == Some(&invite_code))
+                    .ok_or(JoinRejectReason::InvalidInviteCode)?
+            },
+            (None, None) => return Err(JoinRejectReason::RoomNotFound),
+        };
+
+        room.admit(access)?;
+        Ok(room.id)
+    }
+
+    // Every way into a room goes through here, so guessing passwords and invite codes is throttled alike
+    fn check_access(&mut self, address: &SocketAddr, access: &RoomAccess) -> Result<RoomId, JoinRejectReason> {
+        let ip = address.ip();
+        let now = Instant::now();
+
+        if self.join_throttle.is_blocked(ip, now) {
+            return Err(JoinRejectReason::TooManyAttempts);
+        }
+
+        let result = self.find_room(access);
+
+        if let Err(JoinRejectReason::WrongPassword | JoinRejectReason::InviteOnly | JoinRejectReason::InvalidInviteCode) = result {
+            if self.join_throttle.record_failure(ip, now) == FAILED_JOIN_LIMIT {
+                warn!("{} failed joins from {}, ignoring its joins for {:?}", FAILED_JOIN_LIMIT, ip, FAILED_JOIN_BACKOFF);
+            }
+        }
+
+        result
+    }
+
+    fn join_room(&mut self, packet_sender: &Sender<Packet>, client: &Client, access: &RoomAccess) {
+        match self.check_access(&client.address, access) {
+            Ok(room_id) => self.enter_room(packet_sender, client, room_id),
+            Err(reason) => {
+                warn!("Player {} was not let into a room: {}", client.id, reason);
+
+                self.send_lobby_reply(packet_sender, client, &LobbyReply::JoinRejected {
+                    room: access.room,
+                    reason,
+                });
+            },
+        }
+    }
+
+    fn enter_room(&mut self, packet_sender: &Sender<Packet>, client: &Client, room_id: RoomId) {
+        if self.get_room(&client.address).is_some_and(|room| room.id == room_id) {
+            return;
+        }
+        self.leave_room(packet_sender, &client.address);
+
+        let index = match self.lobby.iter().position(|lobby_client| lobby_client.id == client.id) {
+            Some(index) => index,
+            None => return,
+        };
+
+        // The player starts over in the new room
+        let mut client = self.lobby.remove(index);
+        client.team = TeamKind::SpecTeam;
+        client.acked_tick = None;
+        client.input_sequence = None;
+
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        info!("Player {} joined room {} ({})", client.id, room.id, room.name);
+
+        room.add_client(client.clone());
+        room.send_command(NetworkCommand::AddPlayer {
+            id: client.id,
+            nickname: client.nickname.clone(),
+        }).unwrap();
+
+        let invite_code = room.invite_code.clone();
+        let room = room.info();
+        self.send_lobby_reply(packet_sender, &client, &LobbyReply::Joined { room, invite_code });
+    }
+
+    fn leave_room(&mut self, packet_sender: &Sender<Packet>, address: &SocketAddr) {
+        let room = match self.get_room(address) {
+            Some(room) => room,
+            None => return,
+        };
+
+        let room_id = room.id;
+        let client = room.remove_client(address).unwrap();
+        info!("Player {} left room {} ({})", client.id, room.id, room.name);
+
+        self.send_lobby_reply(packet_sender, &client, &LobbyReply::Left { room: room_id });
+
+        self.lobby.push(client);
+        self.close_room_if_empty(room_id);
+    }
+
+    fn send_handshake_reply(&self, packet_sender: &Sender<Packet>, address: &SocketAddr, reply: &HandshakeReply) {
+        let handshake_reply_message = Message {
+            kind: MessageKind::Data,
+            payload: serde_json::to_string(reply).unwrap(),
+        };
+
+        // Clients of another protocol version may predate the acked laminar header, they get the bare one
+        let delivery = match reply {
+            HandshakeReply::Rejected { reason: RejectReason::UnsupportedProtocolVersion, .. } => Delivery::Unreliable,
+            _ => Delivery::of(reply.action()),
+        };
+
+        let data_message = serde_json::to_string(&handshake_reply_message).unwrap().into_bytes();
+        let packet = delivery.packet(*address, data_message);
+        packet_sender.send(packet).unwrap();
+    }
+
+    fn handle_message(&mut self, msg: &Message, address: &SocketAddr, packet_sender: &Sender<Packet>) -> Result<(), DecodeError> {
         match msg.kind {
             MessageKind::Connect => {
+                let handshake: Handshake = msg.decode_payload()?;
+
+                // The handshake itself is not logged, it carries session tokens and room passwords
                 info!(
-                    "Received connect message: {:?} from ip: {:?}",
-                    msg, ip_address
+                    "Received handshake from {:?} (build {:?}, protocol version {})",
+                    address, handshake.client_build, handshake.protocol_version
                 );
 
-                let data: Value = serde_json::from_str(&msg.payload).unwrap();
-                let nickname = data["nickname"].as_str().unwrap();
+                let reconnect_grace = self.config.network.reconnect_grace();
+                let session = handshake.session_token.as_ref().and_then(|session_token| {
+                    self.clients()
+                        .find(|client| client.session_token == *session_token && client.disconnected_at.is_none_or(|disconnected_at| disconnected_at.elapsed() <= reconnect_grace))
+                        .map(|client| client.id)
+                });
+
+                // A parked player whose client restarted without its token is not coming back
+                let abandoned = self.clients()
+                    .find(|client| client.address == *address && client.disconnected_at.is_some())
+                    .is_some_and(|client| session != Some(client.id));
+                if abandoned {
+                    self.disconnect_client(address);
+                }
+
+                let existing = self.clients().find(|client| client.address == *address).map(|client| client.id);
+
+                // A resumed player stays where it was, the room to join only matters for new players
+                let room = match (&handshake.join, session) {
+                    (Some(access), None) => Some(self.check_access(address, access)),
+                    _ => None,
+                };
+
+                let rejection = if handshake.protocol_version != PROTOCOL_VERSION {
+                    Some(RejectReason::UnsupportedProtocolVersion)
+                } else if existing.is_some() && existing != session {
+                    Some(RejectReason::AlreadyConnected)
+                } else if let Some(Err(reason)) = &room {
+                    Some(RejectReason::Room(reason.clone()))
+                } else {
+                    None
+                };
+
+                if let Some(reason) = rejection {
+                    warn!(
+                        "Rejected {:?} (build {:?}, protocol version {}): {}",
+                        address, handshake.client_build, handshake.protocol_version, reason
+                    );
+
+                    self.send_handshake_reply(packet_sender, address, &HandshakeReply::Rejected {
+                        protocol_version: PROTOCOL_VERSION,
+                        reason,
+                    });
+                    return Ok(());
+                }
+
+                // Only the features both sides understand are enabled for this client
+                let capabilities: Vec<String> = handshake.capabilities.into_iter()
+                    .filter(|capability| SERVER_CAPABILITIES.contains(&capability.as_str()))
+                    .collect();
+
+                let codec = Codec::negotiate(&capabilities);
+
+                if let Some(id) = session {
+                    // The client starts over on its side, only the player in the simulation is kept
+                    let client = self.get_client_by_id(id).unwrap();
+                    info!(
+                        "Player {} resumed its session from {:?} (previously {:?}) with capabilities: {:?}, codec: {:?}",
+                        client.id, address, client.address, capabilities, codec
+                    );
+
+                    client.address = *address;
+                    client.codec = codec;
+                    client.acked_tick = None;
+                    client.input_sequence = None;
+                    client.malformed_packets = 0;
+                    client.disconnected_at = None;
+
+                    let session_token = client.session_token.clone();
+
+                    // A player that was waiting in the lobby keeps its id, but has no room to go back to
+                    let resumed = self.get_room(address).is_some();
+
+                    self.send_handshake_reply(packet_sender, address, &HandshakeReply::Accepted {
+                        protocol_version: PROTOCOL_VERSION,
+                        player_id: id,
+                        session_token,
+                        resumed,
+                        capabilities,
+                    });
+
+                    if let Some(room) = self.get_room(address) {
+                        room.update_host();
+                        room.send_command(NetworkCommand::ResumePlayer { id }).unwrap();
+                    }
+                    return Ok(());
+                }
+
+                let id = self.next_player_id();
+                let session_token = format!("{:032x}", rand::random::<u128>());
 
-                self.add_client(Client {
-                    ip_address: ip_address.clone(),
-                    nickname: String::from(nickname),
-                    team: TeamKind::SpecTeam
+                info!(
+                    "Accepted {:?} as player {} (build {:?}) with capabilities: {:?}, codec: {:?}",
+                    address, id, handshake.client_build, capabilities, codec
+                );
+
+                self.send_handshake_reply(packet_sender, address, &HandshakeReply::Accepted {
+                    protocol_version: PROTOCOL_VERSION,
+                    player_id: id,
+                    session_token: session_token.clone(),
+                    resumed: false,
+                    capabilities,
                 });
 
-                self.send_command(NetworkCommand {
-                    kind: CommandKind::AddPlayer,
-                    data: json!({
-                        "name": ip_address.clone(),
-                        "nickname": String::from(nickname)
-                    }),
-                }).unwrap();
+                // Players wait in the lobby until they join or create a room
+                let client = Client {
+                    id,
+                    address: *address,
+                    nickname: handshake.nickname,
+                    team: TeamKind::SpecTeam,
+                    codec,
+                    acked_tick: None,
+                    input_sequence: None,
+                    malformed_packets: 0,
+                    session_token,
+                    disconnected_at: None,
+                };
+                self.lobby.push(client.clone());
+
+                if let Some(Ok(room_id)) = room {
+                    self.enter_room(packet_sender, &client, room_id);
+                }
             }
             MessageKind::Data => {
-                let clients = self.get_clients().clone();
-                for client in clients.iter() {
-                    if client.ip_address.eq(ip_address) {
-                        let payload: Value = serde_json::from_str(&msg.payload).unwrap();
-                        let action = payload["action"].as_str().unwrap();
-
-                        match action {
-                            "CHANGE_PLAYER_TEAM" => {
-                                if let Ok(team) = TeamKind::from_str(payload["team"].as_str().unwrap()) {
-                                    if let Some(client) = self.get_client(ip_address) {
-                                        client.assign_team(team.clone());
-
-                                        self.send_command(NetworkCommand {
-                                            kind: CommandKind::ChangePlayerTeam,
-                                            data: json!({ 
-                                                "name": ip_address.clone(),
-                                                "team": team
-                                            })
-                                        }).unwrap();
-                                    }
-                                }
-                            },
-                            "PLAYER_MOVED" => {
-                                if let Some(position) = payload["position"].as_object() {
-                                    let data = json!({
-                                        "name": ip_address.clone(),
-                                        "velocity": position
-                                    });
-    
-                                    self.send_command(NetworkCommand {
-                                        kind: CommandKind::MovePlayer,
-                                        data,
-                                    }).unwrap();
-                                }
-                            },
-                            "PLAYER_KICKED" => {
-                                let data = json!({
-                                    "name": ip_address.clone()
-                                });
-
-                                self.send_command(NetworkCommand {
-                                    kind: CommandKind::KickBall,
-                                    data,
-                                }).unwrap();
-                            },
-                            "PLAYER_DISCONNECTED" => {
-                                self.delete_client(client);
-
-                                let data = json!({
-                                    "name": ip_address.clone()
-                                });
-
-                                self.send_command(NetworkCommand {
-                                    kind: CommandKind::DisconnectPlayer,
-                                    data,
-                                }).unwrap();
-                            },
-                            _ => trace!("Unknown action: {:?}", payload)
-                        }
-                    } else if self.get_client(ip_address).is_some() {
-                        let behavior: Behavior = serde_json::from_str(&msg.payload).unwrap();
-                        match behavior.action.as_str() {
-                            "ENEMY_MOVED" => {
-                                if let Some(position) = behavior.position {
-                                    let data = json!({
-                                        "name": ip_address.clone(),
-                                        "velocity": position
-                                    });
-    
-                                    self.send_command(NetworkCommand {
-                                        kind: CommandKind::MoveEnemy,
-                                        data,
-                                    }).unwrap();
-                                }
-                            },
-                            _ => trace!("Unknown action: {:?}", behavior)
-                        }
+                let client = match self.get_client(address) {
+                    Some(client) => client.clone(),
+                    None => return Ok(()),
+                };
+
+                if client.disconnected_at.is_some() {
+                    debug!("Ignored data of player {}, it has to resume its session first", client.id);
+                    return Ok(());
+                }
+
+                let payload: Value = msg.decode_payload()?;
+
+                payload["action"].as_str().ok_or(DecodeError::MissingField("action"))?;
+                let action = match Action::deserialize(&payload["action"]) {
+                    Ok(action) => action,
+                    Err(_) => {
+                        trace!("Unknown action: {:?}", payload);
+                        return Ok(());
+                    },
+                };
+
+                // Lobby requests are answered wherever the player is, everything else is for its room
+                if LobbyRequest::handles(action) {
+                    let request: LobbyRequest = msg.decode_payload()?;
+                    self.handle_lobby_request(packet_sender, &client, request);
+                    return Ok(());
+                }
+
+                match action {
+                    Action::PlayerDisconnected => {
+                        info!("Player {} left", client.id);
+                        self.disconnect_client(address);
+                    },
+                    _ => match self.get_room(address) {
+                        Some(room) => room.handle_message(msg, address, action, &payload, packet_sender)?,
+                        None => debug!("Ignored {:?} from player {}, it is not in a room", action, client.id),
                     }
                 }
-                
             }
             MessageKind::Timeout => {
-                // println!(
-                //     "Received timeout message: {:?} from ip: {:?}",
-                //     msg, ip_address
-                // );
+                let reconnect_grace = self.config.network.reconnect_grace();
+
+                let id = match self.get_client(address).filter(|client| client.disconnected_at.is_none()) {
+                    Some(client) => {
+                        client.disconnected_at = Some(Instant::now());
+                        client.id
+                    },
+                    None => return Ok(()),
+                };
+                info!("Player {} timed out, it can resume its session within {:?}", id, reconnect_grace);
+
+                if let Some(room) = self.get_room(address) {
+                    room.update_host();
+                    room.send_command(NetworkCommand::ParkPlayer { id }).unwrap();
+                }
             }
         }
-    }
 
-    fn handle_socket_event(&mut self, event_receiver: &Receiver<SocketEvent>) {
-        match event_receiver.try_recv() {
-            Ok(socket_event) => match socket_event {
-                SocketEvent::Packet(packet) => {
-                    let payload = String::from_utf8_lossy(packet.payload());
-                    let message: Message = serde_json::from_str(&payload).unwrap();
-                    let ip_address = packet.addr().to_string();
+        Ok(())
+    }
 
-                    self.handle_message(&message, &ip_address);
-                }
-                SocketEvent::Timeout(ip_address) => {
-                    let message = Message {
-                        kind: MessageKind::Timeout,
-                        payload: String::new(),
-                    };
+    fn handle_malformed_packet(&mut self, address: &SocketAddr, error: DecodeError) {
+        let malformed_packets = match self.get_client(address) {
+            Some(client) => {
+                warn!("Dropped malformed packet from player {}: {}", client.id, error);
 
-                    self.handle_message(&message, &ip_address.to_string());
-                }
-                _ => (),
+                client.malformed_packets += 1;
+                client.malformed_packets
             },
-            Err(e) => {
-                if !e.is_empty() {
-                    error!("Something went wrong when receiving, error: {:?}", e)
-                }
-            }
-        }
-    }
-
-    fn handle_telemetrics(&mut self, packet_sender: &Sender<Packet>, last_position: &mut HashMap<String, HashMap<String, Vector<f32>>>, elapsed: Duration) {
-        let (_, receiver) = &self.channels.get("network").unwrap();
-        let response_duration = 30;
-
-        match receiver.try_recv() {
-            Ok(command) => {
-                match command.kind {
-                    CommandKind::AddPlayerAck => {
-                        let player_data = json!(command.data);
-
-                        let (x, y) = (player_data["translation"]["x"].as_f64().unwrap() as f32, player_data["translation"]["y"].as_f64().unwrap() as f32);
-                        let new_player_name = String::from(player_data["name"].as_str().unwrap());
-                        let new_player_nickname = String::from(player_data["nickname"].as_str().unwrap());
-
-                        let clients = self.get_clients().clone();
-
-                        for client in &clients {
-                            let add_player_ack_message = Message {
-                                kind: MessageKind::Data,
-                                payload: json!({
-                                    "action": String::from("PLAYER_ADD_ACK"),
-                                    "name": new_player_name,
-                                    "nickname": new_player_nickname,
-                                    "position": { "x": x, "y": y }
-                                }).to_string(),
-                            };
-
-                            let data_message = serde_json::to_string(&add_player_ack_message).unwrap().into_bytes();
-                            let unreliable = Packet::unreliable(client.ip_address.parse().unwrap(), data_message.clone());
-                            packet_sender.send(unreliable).unwrap();
-                        }
-
-                        for client in &clients {
-                            if client.ip_address.ne(&new_player_name) {
-                                let last_client_position = last_position.get_mut(&client.ip_address).unwrap();
-
-                                if let Some(last_player_position) = last_client_position.get("player_position") {
-                                    let existing_player_message = Message {
-                                        kind: MessageKind::Data,
-                                        payload: json!({
-                                            "action": String::from("PLAYER_ADD_ACK"),
-                                            "name": client.ip_address.clone(),
-                                            "nickname": client.nickname,
-                                            "position": { "x": last_player_position.x, "y": last_player_position.y },
-                                            "team": client.team
-                                        }).to_string(),
-                                    };
-    
-                                    let data_message = serde_json::to_string(&existing_player_message).unwrap().into_bytes();
-                                    let unreliable = Packet::unreliable(new_player_name.parse().unwrap(), data_message.clone());
-                                    packet_sender.send(unreliable).unwrap();
-                                }
-                            }
-                        }
-                    },
-                    CommandKind::ChangePlayerTeamAck => {
-                        let player_data = json!(command.data);
-                        let player_name = player_data["name"].as_str().unwrap();
-                        let team = player_data["team"].as_str().unwrap();
-
-                        let clients = self.get_clients().clone();
-
-                        for client in &clients {
-                            let add_player_ack_message = Message {
-                                kind: MessageKind::Data,
-                                payload: json!({
-                                    "action": String::from("CHANGE_PLAYER_TEAM_ACK"),
-                                    "name": player_name,
-                                    "team": team,
-                                }).to_string(),
-                            };
-
-                            let data_message = serde_json::to_string(&add_player_ack_message).unwrap().into_bytes();
-                            let unreliable = Packet::unreliable(client.ip_address.parse().unwrap(), data_message.clone());
-                            packet_sender.send(unreliable).unwrap();
-                        }
-
-                        for client in &clients {
-                            if client.ip_address.ne(&player_name) {
-                                let existing_player_message = Message {
-                                    kind: MessageKind::Data,
-                                    payload: json!({
-                                        "action": String::from("CHANGE_PLAYER_TEAM_ACK"),
-                                        "name": client.ip_address.clone(),
-                                        "team": client.team
-                                    }).to_string(),
-                                };
-
-                                let data_message = serde_json::to_string(&existing_player_message).unwrap().into_bytes();
-                                let unreliable = Packet::unreliable(player_name.parse().unwrap(), data_message.clone());
-                                packet_sender.send(unreliable).unwrap();
-                            }
-                        }
-                    },
-                    CommandKind::Telemetrics => {
-                        fn handle_ball(packet_sender: &Sender<Packet>, client_name: String, telemetrics: &Value, last_position: &mut HashMap<String, HashMap<String, Vector<f32>>>) {
-                            let ball_telemetrics = &telemetrics["ball"];
-                            let (x, y) = (ball_telemetrics["translation"]["x"].as_f64().unwrap() as f32, ball_telemetrics["translation"]["y"].as_f64().unwrap() as f32);
-                            let last_client_position = last_position.get_mut(&client_name).unwrap();
-
-                            let position_message = Message {
-                                kind: MessageKind::Data,
-                                payload: serde_json::to_string(&Behavior {
-                                    action: String::from("BALL_MOVED"),
-                                    position: Some(Position { x, y })
-                                }).unwrap()
-                            };
-
-                            let data_message = serde_json::to_string(&position_message).unwrap().into_bytes();
-                            let unreliable = Packet::unreliable(client_name.parse().unwrap(), data_message.clone());
-
-                            if let Some(last_ball_position) = last_client_position.get_mut("ball_position") {
-                                if last_ball_position.x != x || last_ball_position.y != y {
-                                    packet_sender.send(unreliable).unwrap();
-                                    *last_ball_position = vector![x, y];
-                                }
-                            } else {
-                                packet_sender.send(unreliable).unwrap();
-                                last_client_position.insert(String::from("ball_position"), vector![x, y]);
-                            }
-                        }
-
-                        fn handle_player(packet_sender: &Sender<Packet>, client_name: String, telemetrics: &Value, last_position: &mut HashMap<String, HashMap<String, Vector<f32>>>) {
-                            let player_telemetrics = &telemetrics[client_name.clone()];
-
-                            if !player_telemetrics.is_null() {
-                                let (x, y) = (player_telemetrics["translation"]["x"].as_f64().unwrap().round() as f32, player_telemetrics["translation"]["y"].as_f64().unwrap().round() as f32);
-                                let last_client_position = last_position.get_mut(&client_name).unwrap();
-
-                                let position_message = Message {
-                                    kind: MessageKind::Data,
-                                    payload: serde_json::to_string(&Behavior {
-                                        action: String::from("PLAYER_MOVED"),
-                                        position: Some(Position { x, y })
-                                    }).unwrap()
-                                };
-    
-                                let data_message = serde_json::to_string(&position_message).unwrap().into_bytes();
-                                let unreliable = Packet::unreliable(client_name.parse().unwrap(), data_message.clone());
-
-                                if let Some(last_player_position) = last_client_position.get_mut("player_position") {
-                                    if last_player_position.x != x || last_player_position.y != y {
-                                        packet_sender.send(unreliable).unwrap();
-                                        *last_player_position = vector![x, y];
-                                    }
-                                } else {
-                                    packet_sender.send(unreliable).unwrap();
-                                    last_client_position.insert(String::from("player_position"), vector![x, y]);
-                                }
-                            }
-                        }
-
-                        fn handle_enemy(packet_sender: &Sender<Packet>, client_name: String, enemy_name: String, telemetrics: &Value, last_position: &mut HashMap<String, HashMap<String, Vector<f32>>>) {
-                            let player_telemetrics = &telemetrics[enemy_name.clone()];
-                            if !player_telemetrics.is_null() {
-                                if let Some(last_client_position) = last_position.get_mut(&client_name) {
-                                    let (x, y) = (player_telemetrics["translation"]["x"].as_f64().unwrap().round() as f32, player_telemetrics["translation"]["y"].as_f64().unwrap().round() as f32);
-
-                                    let position_message = Message {
-                                        kind: MessageKind::Data,
-                                        payload: json!({
-                                            "action": String::from("ENEMY_MOVED"),
-                                            "name": enemy_name,
-                                            "position": { "x": x, "y": y }
-                                        }).to_string()
-                                    };
-        
-                                    let data_message = serde_json::to_string(&position_message).unwrap().into_bytes();
-                                    let unreliable = Packet::unreliable(client_name.parse().unwrap(), data_message.clone());
-
-                                    if let Some(last_enemy_position) = last_client_position.get_mut(enemy_name.as_str()) {
-                                        if last_enemy_position.x != x || last_enemy_position.y != y {
-                                            packet_sender.send(unreliable).unwrap();
-                                            *last_enemy_position = vector![x, y];
-                                        }
-                                    } else {
-                                        packet_sender.send(unreliable).unwrap();
-                                        last_client_position.insert(enemy_name, vector![x, y]);
-                                    }
-                                }
-                            }
-                        }
-
-                        let clients = self.get_clients().clone();
-
-                        for client in &clients {
-                            if last_position.get(&client.ip_address).is_none() {
-                                last_position.insert(client.ip_address.clone(), HashMap::new());
-                            }
-
-                            let telemetrics = json!(command.data);
-
-                            if elapsed.as_millis() % response_duration == 0 {
-                                handle_ball(packet_sender, client.ip_address.clone(), &telemetrics, last_position);
-                                handle_player(packet_sender, client.ip_address.clone(), &telemetrics, last_position);
-                            }
-
-                            for enemy in &clients {
-                                if enemy.ip_address.eq(&client.ip_address) {
-                                    continue;
-                                }
-
-                                if elapsed.as_millis() % response_duration == 0 {
-                                    handle_enemy(packet_sender, client.ip_address.clone(), enemy.ip_address.clone(), &telemetrics, last_position);
-                                }
-                            }
-                        }
-                    },
-                    CommandKind::DisconnectPlayerAck => {
-                        let player_data = json!(command.data);
-                        let player_name = player_data["name"].as_str().unwrap();
-                        let disconnect_player_ack_message = Message {
-                            kind: MessageKind::Data,
-                            payload: json!({
-                                "action": String::from("PLAYER_DISCONNECT_ACK"),
-                                "name": String::from(player_name)
-                            }).to_string(),
-                        };
+            None => {
+                // Anyone can send garbage, only packets of connected players are worth a warning
+                debug!("Dropped malformed packet from {:?}: {}", address, error);
+                return;
+            },
+        };
 
-                        let clients = self.get_clients().clone();
+        if malformed_packets >= MALFORMED_PACKET_LIMIT {
+            warn!("Disconnecting {:?} after {} malformed packets", address, malformed_packets);
 
-                        for client in &clients {
-                            let data_message = serde_json::to_string(&disconnect_player_ack_message).unwrap().into_bytes();
-                            let unreliable = Packet::unreliable(client.ip_address.parse().unwrap(), data_message.clone());
-                            packet_sender.send(unreliable).unwrap();
-                        }
+            self.disconnect_client(address);
+        }
+    }
 
-                        last_position.remove(player_name);
-                    },
-                    _ => trace!("Unknown command: {:?}", command.kind)
-                }
-            },
-            Err(e) => {
-                if !e.is_empty() {
-                    error!("Something went wrong when receiving, error: {:?}", e)
+    fn handle_socket_events(&mut self, event_receiver: &Receiver<SocketEvent>, packet_sender: &Sender<Packet>) {
+        self.socket_metrics.record_depth(event_receiver.len());
+
+        let socket_events: Vec<SocketEvent> = event_receiver.try_iter().take(COMMAND_BUDGET).collect();
+        let received = socket_events.len();
+
+        for socket_event in socket_events {
+            self.handle_socket_event(socket_event, packet_sender);
+        }
+
+        self.socket_metrics.record_processed(received, received);
+        self.socket_metrics.report();
+    }
+
+    fn handle_socket_event(&mut self, socket_event: SocketEvent, packet_sender: &Sender<Packet>) {
+        match socket_event {
+            SocketEvent::Packet(packet) => {
+                let address = packet.addr();
+
+                let result = Message::decode(packet.payload())
+                    .and_then(|message| self.handle_message(&message, &address, packet_sender));
+
+                if let Err(error) = result {
+                    self.handle_malformed_packet(&address, error);
                 }
             }
+            // Laminar sends a disconnect right after the timeout of an established connection,
+            // the second one finds the player parked already
+            SocketEvent::Timeout(address) | SocketEvent::Disconnect(address) => {
+                let message = Message {
+                    kind: MessageKind::Timeout,
+                    payload: String::new(),
+                };
+
+                let _ = self.handle_message(&message, &address, packet_sender);
+            }
+            SocketEvent::Connect(address) => debug!("Connection established with {:?}", address),
+        }
+    }
+
+    // Reloaded tuning is passed on to every room, rooms opened later start with it
+    fn handle_commands(&mut self) {
+        let commands: Vec<NetworkCommand> = {
+            let (_, receiver) = &self.channels.get("network").unwrap();
+            receiver.try_iter().collect()
+        };
+
+        for command in commands {
+            match command {
+                NetworkCommand::UpdateSettings { settings, movement, changes } => {
+                    self.config.physics = settings.clone();
+                    self.config.movement = movement;
+
+                    for room in self.rooms.values_mut() {
+                        room.send_command(NetworkCommand::UpdateSettings {
+                            settings: settings.clone(),
+                            movement,
+                            changes: changes.clone(),
+                        }).unwrap();
+                    }
+                },
+                _ => trace!("Unknown command: {:?}", command)
+            }
         }
     }
 
-    fn start_server(&mut self, server_ip_address: &str) {
-        match Socket::bind(server_ip_address) {
+    fn start_server(&mut self) {
+        let server_ip_address = self.config.network.address();
+        let config = Config {
+            idle_connection_timeout: self.config.network.connection_timeout(),
+            ..Config::default()
+        };
+
+        match Socket::bind_with_config(server_ip_address, config) {
             Ok(mut socket) => {
                 let (packet_sender, event_receiver) =
                     (socket.get_packet_sender(), socket.get_event_receiver());
@@ -791,18 +2330,21 @@ impl Network {
 
                 info!("Waiting for connection at: {:?}", server_ip_address);
 
+                for name in self.config.rooms.permanent.clone() {
+                    let access = self.config.rooms.access.get(&name).cloned().unwrap_or_default();
+                    self.open_room(name, true, access);
+                }
+
                 let delay = Duration::from_nanos(1);
-                let mut start = Instant::now();
-                let mut position_state: HashMap<String, HashMap<String, Vector<f32>>> = HashMap::new();
 
                 loop {
-                    let duration = start.elapsed();
+                    self.handle_socket_events(&event_receiver, &packet_sender);
+                    self.handle_commands();
+                    self.remove_expired_clients();
+                    self.join_throttle.forget(Instant::now());
 
-                    self.handle_socket_event(&event_receiver);
-                    self.handle_telemetrics(&packet_sender, &mut position_state, duration);
-
-                    if duration.as_millis() > 120 {
-                        start = Instant::now();
+                    for room in self.rooms.values_mut() {
+                        room.handle_telemetrics(&packet_sender);
                     }
 
                     thread::sleep(delay);
@@ -813,56 +2355,79 @@ impl Network {
     }
 }
 
-#[derive(Clone)]
-struct State {}
-
-impl State {
-    // fn add_client(&mut self, client: Client) {
-    //     self.clients.push(client);
-    // }
-
-    // fn get_clients(&self) -> Vec<Client> {
-    //     self.clients.clone()
-    // }
+struct World {
+    options: Options,
+    config: ServerConfig,
 }
 
-struct World {}
-
 impl World {
-    fn setup_physics_engine(
+    fn setup_network(
         &self,
         channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)>,
     ) -> JoinHandle<()> {
-        let mut physics_engine = PhysicsEngine {
-            channels,
-            handles: HashMap::new(),
-            bodies: RigidBodySet::new(),
-            colliders: ColliderSet::new(),
-            joints: JointSet::new(),
-            islands: IslandManager::new(),
-        };
-
-        thread::spawn(move || physics_engine.start_simulation())
+        let mut network = Network::new(channels, self.config.clone());
+        thread::spawn(move || network.start_server())
     }
 
-    fn setup_network(
+    // Tuning changes in the config file are handed to every room, each physics thread applies them between two ticks
+    fn setup_config_watcher(
         &self,
         channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)>,
-    ) -> JoinHandle<()> {
-        let mut network = Network { channels, clients: Vec::new() };
-        thread::spawn(move || network.start_server("127.0.0.1:12350"))
+    ) -> Option<JoinHandle<()>> {
+        let path = self.options.config.clone()?;
+        let options = self.options.clone();
+        let mut config = self.config.clone();
+
+        let modified = move || fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+        let mut last_modified = modified();
+
+        Some(thread::spawn(move || loop {
+            thread::sleep(CONFIG_POLL_INTERVAL);
+
+            let current_modified = modified();
+            if current_modified == last_modified {
+                continue;
+            }
+            last_modified = current_modified;
+
+            let loaded = match ServerConfig::load(&options) {
+                Ok(loaded) => loaded,
+                Err(error) => {
+                    warn!("Ignored reloaded configuration: {}", error);
+                    continue;
+                }
+            };
+
+            let (applied, ignored) = config.reload(&loaded);
+
+            for change in ignored.iter() {
+                warn!("Setting {}, restart the server to apply it", change);
+            }
+            if applied.is_empty() {
+                continue;
+            }
+            for change in applied.iter() {
+                info!("Setting {}", change);
+            }
+
+            let (sender, _) = &channels.get("network").unwrap();
+            sender.send(NetworkCommand::UpdateSettings {
+                settings: config.physics.clone(),
+                movement: config.movement,
+                changes: applied,
+            }).unwrap();
+        }))
     }
 
     fn run(self) {
         let mut channels: HashMap<String, (Sender<NetworkCommand>, Receiver<NetworkCommand>)> = HashMap::new();
 
         channels.insert(String::from("network"), unbounded());
-        channels.insert(String::from("physics"), unbounded());
 
-        let handles = vec![
-            self.setup_physics_engine(channels.clone()),
+        let mut handles = vec![
             self.setup_network(channels.clone()),
         ];
+        handles.extend(self.setup_config_watcher(channels.clone()));
 
         for handle in handles {
             handle.join().unwrap();
@@ -870,9 +2435,549 @@ impl World {
     }
 }
 
+// Takes as long for a wrong first character as for a wrong last one, so the time of a reply tells nothing about the password
+fn passwords_match(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    let mut difference = expected.len() ^ given.len();
+
+    for (index, byte) in expected.iter().enumerate() {
+        difference |= (byte ^ given.get(index).copied().unwrap_or(0)) as usize;
+    }
+
+    difference == 0
+}
+
+// Sequences wrap around, one that is less than half the range ahead of the last one is newer
+fn is_newer_sequence(sequence: u32, last_sequence: u32) -> bool {
+    let ahead = sequence.wrapping_sub(last_sequence);
+    ahead != 0 && ahead <= i32::MAX as u32
+}
+
+// Invite codes are typed in by players, so spaces and case do not matter
+fn normalize_invite_code(invite_code: &str) -> String {
+    invite_code.trim().to_uppercase()
+}
+
 fn main() { 
     env_logger::init();
 
-    let world = World {};
+    let options = Options::from_args();
+    let config = match ServerConfig::load(&options) {
+        Ok(config) => config,
+        Err(error) => {
+            error!("Invalid configuration: {}", error);
+            process::exit(1);
+        }
+    };
+
+    let world = World { options, config };
     world.run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_match_only_when_equal() {
+        assert!(passwords_match("secret", "secret"));
+        assert!(!passwords_match("secret", "secreT"));
+        assert!(!passwords_match("secret", "secret2"));
+        assert!(!passwords_match("secret", "secre"));
+        assert!(!passwords_match("secret", ""));
+        assert!(passwords_match("", ""));
+    }
+
+    #[test]
+    fn invite_codes_ignore_case_and_spaces() {
+        assert_eq!(normalize_invite_code(" ab3k9q "), "AB3K9Q");
+    }
+
+    fn data(payload: Value) -> Message {
+        Message {
+            kind: MessageKind::Data,
+            payload: payload.to_string(),
+        }
+    }
+
+    fn connect(payload: Value) -> Message {
+        Message {
+            kind: MessageKind::Connect,
+            payload: payload.to_string(),
+        }
+    }
+
+    // Network without a socket, whatever it sends ends up in the returned receiver
+    fn connected_network(address: &SocketAddr) -> (Network, Sender<Packet>, Receiver<Packet>) {
+        let mut network = Network::new(HashMap::new(), ServerConfig::default());
+        let (packet_sender, packet_receiver) = unbounded();
+
+        let handshake = connect(json!({ "protocol_version": PROTOCOL_VERSION, "nickname": "player" }));
+        network.handle_message(&handshake, address, &packet_sender).unwrap();
+
+        (network, packet_sender, packet_receiver)
+    }
+
+    #[test]
+    fn malformed_lobby_requests_are_rejected() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let (mut network, packet_sender, _packet_receiver) = connected_network(&address);
+
+        let join = data(json!({ "action": Action::JoinRoom, "room": "abc", "password": "secret" }));
+        assert!(matches!(network.handle_message(&join, &address, &packet_sender), Err(DecodeError::Malformed(_))));
+
+        let create = data(json!({ "action": Action::CreateRoom, "invite_only": "yes" }));
+        assert!(matches!(network.handle_message(&create, &address, &packet_sender), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn unknown_and_room_actions_outside_a_room_are_ignored() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let (mut network, packet_sender, _packet_receiver) = connected_network(&address);
+
+        assert!(network.handle_message(&data(json!({ "action": "DANCE" })), &address, &packet_sender).is_ok());
+        assert!(network.handle_message(&data(json!({ "action": Action::StartMatch })), &address, &packet_sender).is_ok());
+        assert_eq!(network.handle_message(&data(json!({ "up": true })), &address, &packet_sender), Err(DecodeError::MissingField("action")));
+    }
+
+    #[test]
+    fn throttle_blocks_an_address_after_too_many_failures() {
+        let mut throttle = JoinThrottle::default();
+        let (ip, other_ip) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let now = Instant::now();
+
+        for attempt in 1..FAILED_JOIN_LIMIT {
+            assert_eq!(throttle.record_failure(ip, now), attempt);
+            assert!(!throttle.is_blocked(ip, now));
+        }
+
+        throttle.record_failure(ip, now);
+        assert!(throttle.is_blocked(ip, now));
+        assert!(throttle.is_blocked(ip, now + FAILED_JOIN_BACKOFF / 2));
+        assert!(!throttle.is_blocked(other_ip, now));
+    }
+
+    #[test]
+    fn throttle_starts_over_after_the_backoff() {
+        let mut throttle = JoinThrottle::default();
+        let (ip, recent_ip) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let now = Instant::now();
+
+        for _ in 0..FAILED_JOIN_LIMIT {
+            throttle.record_failure(ip, now);
+        }
+        throttle.record_failure(recent_ip, now + FAILED_JOIN_BACKOFF / 2);
+
+        let later = now + FAILED_JOIN_BACKOFF;
+        assert!(!throttle.is_blocked(ip, later));
+
+        throttle.forget(later);
+        assert_eq!(throttle.record_failure(ip, later), 1);
+        assert_eq!(throttle.record_failure(recent_ip, later), 2);
+    }
+
+    fn access(password: Option<&str>, invite_code: Option<&str>) -> RoomAccess {
+        RoomAccess {
+            room: Some(RoomId(1)),
+            invite_code: invite_code.map(String::from),
+            password: password.map(String::from),
+        }
+    }
+
+    #[test]
+    fn password_rooms_need_the_password() {
+        let mut room = Room::open(RoomId(1), String::from("Test"), false, &ServerConfig::default());
+        room.password = Some(String::from("secret"));
+
+        assert_eq!(room.admit(&access(None, None)), Err(JoinRejectReason::WrongPassword));
+        assert_eq!(room.admit(&access(Some("Secret"), None)), Err(JoinRejectReason::WrongPassword));
+        assert_eq!(room.admit(&access(Some("secret"), None)), Ok(()));
+
+        room.close();
+    }
+
+    #[test]
+    fn invite_only_rooms_need_their_code() {
+        let mut room = Room::open(RoomId(1), String::from("Test"), false, &ServerConfig::default());
+        room.invite_code = Some(String::from("AB3K9Q"));
+
+        assert_eq!(room.admit(&access(None, None)), Err(JoinRejectReason::InviteOnly));
+        assert_eq!(room.admit(&access(None, Some("AB3K9R"))), Err(JoinRejectReason::InvalidInviteCode));
+        assert_eq!(room.admit(&access(None, Some(" ab3k9q"))), Ok(()));
+
+        // A code does not make up for the password
+        room.password = Some(String::from("secret"));
+        assert_eq!(room.admit(&access(None, Some("AB3K9Q"))), Err(JoinRejectReason::WrongPassword));
+        assert_eq!(room.admit(&access(Some("secret"), Some("AB3K9Q"))), Ok(()));
+
+        room.close();
+    }
+
+    #[test]
+    fn guessing_passwords_ends_in_too_many_attempts() {
+        let mut network = Network::new(HashMap::new(), ServerConfig::default());
+        let room_id = network.open_room(String::from("Test"), false, RoomAccessSettings {
+            password: Some(String::from("secret")),
+            invite_only: false,
+        });
+        let (address, other_address) = (SocketAddr::from(([10, 0, 0, 1], 4000)), SocketAddr::from(([10, 0, 0, 2], 4000)));
+
+        for _ in 0..FAILED_JOIN_LIMIT {
+            assert_eq!(network.check_access(&address, &access(Some("guess"), None)), Err(JoinRejectReason::WrongPassword));
+        }
+
+        // Another port of the same address is no way around it, the right password is not let through either
+        let same_ip = SocketAddr::from(([10, 0, 0, 1], 4001));
+        assert_eq!(network.check_access(&same_ip, &access(Some("secret"), None)), Err(JoinRejectReason::TooManyAttempts));
+        assert_eq!(network.check_access(&other_address, &access(Some("secret"), None)), Ok(room_id));
+
+        // Rooms that do not exist are not guesses
+        let missing = RoomAccess { room: Some(RoomId(9)), ..RoomAccess::default() };
+        for _ in 0..FAILED_JOIN_LIMIT {
+            assert_eq!(network.check_access(&other_address, &missing), Err(JoinRejectReason::RoomNotFound));
+        }
+        assert_eq!(network.check_access(&other_address, &access(Some("secret"), None)), Ok(room_id));
+
+        for room in network.rooms.values_mut() {
+            room.close();
+        }
+    }
+
+    fn match_settings() -> MatchSettings {
+        MatchSettings {
+            time_limit: Duration::from_secs(60),
+            score_limit: 2,
+            kickoff_countdown: Duration::from_secs(3),
+            goal_delay: Duration::from_secs(2),
+            halftime_duration: Duration::from_secs(5),
+            full_time_duration: Duration::from_secs(4),
+        }
+    }
+
+    fn started_match() -> MatchState {
+        let mut match_state = MatchState::new(match_settings());
+        assert!(match_state.start(match_settings()));
+        assert_eq!(match_state.phase, MatchPhase::Kickoff);
+
+        assert!(!match_state.update(Duration::from_secs(2)));
+        assert!(match_state.update(Duration::from_secs(1)));
+        assert_eq!(match_state.phase, MatchPhase::Playing);
+
+        match_state
+    }
+
+    #[test]
+    fn goals_lead_back_to_a_kickoff_of_the_other_team() {
+        let mut match_state = started_match();
+
+        assert!(match_state.goal(&TeamKind::RedTeam));
+        assert_eq!(match_state.phase, MatchPhase::GoalScored);
+        assert_eq!(match_state.score.red_team, 1);
+        assert_eq!(match_state.kickoff_team, TeamKind::BlueTeam);
+
+        // The ball can roll on into the goal during the celebration, it does not count twice
+        assert!(!match_state.goal(&TeamKind::RedTeam));
+        assert_eq!(match_state.score.red_team, 1);
+
+        assert!(match_state.update(Duration::from_secs(2)));
+        assert_eq!(match_state.phase, MatchPhase::Kickoff);
+        assert!(match_state.update(Duration::from_secs(3)));
+        assert_eq!(match_state.phase, MatchPhase::Playing);
+    }
+
+    #[test]
+    fn halftime_hands_the_kickoff_to_the_blue_team() {
+        let mut match_state = started_match();
+
+        assert!(!match_state.update(Duration::from_secs(29)));
+        assert!(match_state.update(Duration::from_secs(1)));
+        assert_eq!(match_state.phase, MatchPhase::Halftime);
+        assert_eq!(match_state.half, 1);
+
+        assert!(match_state.update(Duration::from_secs(5)));
+        assert_eq!(match_state.phase, MatchPhase::Kickoff);
+        assert_eq!(match_state.half, 2);
+        assert_eq!(match_state.kickoff_team, TeamKind::BlueTeam);
+    }
+
+    #[test]
+    fn time_limit_ends_the_match() {
+        let mut match_state = started_match();
+
+        match_state.update(Duration::from_secs(30));
+        match_state.update(Duration::from_secs(5));
+        match_state.update(Duration::from_secs(3));
+        assert_eq!((match_state.phase, match_state.half), (MatchPhase::Playing, 2));
+
+        assert!(!match_state.update(Duration::from_secs(29)));
+        assert!(match_state.update(Duration::from_secs(1)));
+        assert_eq!(match_state.phase, MatchPhase::FullTime);
+
+        assert!(match_state.update(Duration::from_secs(4)));
+        assert_eq!(match_state.phase, MatchPhase::Lobby);
+    }
+
+    #[test]
+    fn score_limit_ends_the_match() {
+        let mut match_state = started_match();
+
+        match_state.goal(&TeamKind::BlueTeam);
+        match_state.update(Duration::from_secs(2));
+        match_state.update(Duration::from_secs(3));
+        match_state.goal(&TeamKind::BlueTeam);
+
+        assert_eq!(match_state.phase, MatchPhase::FullTime);
+        assert_eq!(match_state.score.blue_team, 2);
+    }
+
+    #[test]
+    fn matches_without_limits_keep_running() {
+        let mut match_state = MatchState::new(MatchSettings::default());
+        match_state.start(MatchSettings { time_limit: Duration::ZERO, score_limit: 0, ..match_settings() });
+        match_state.update(Duration::from_secs(3));
+
+        assert!(!match_state.update(Duration::from_secs(3600)));
+        for _ in 0..10 {
+            match_state.goal(&TeamKind::RedTeam);
+            match_state.update(Duration::from_secs(2));
+            match_state.update(Duration::from_secs(3));
+        }
+
+        assert_eq!(match_state.phase, MatchPhase::Playing);
+        assert_eq!(match_state.score.red_team, 10);
+    }
+
+    #[test]
+    fn stopped_matches_start_over() {
+        let mut match_state = MatchState::new(match_settings());
+        assert!(!match_state.stop());
+
+        let mut match_state = started_match();
+        assert!(!match_state.start(match_settings()));
+
+        match_state.goal(&TeamKind::RedTeam);
+        match_state.update(Duration::from_secs(2));
+        assert!(match_state.stop());
+        assert_eq!(match_state.phase, MatchPhase::Lobby);
+        assert!(!match_state.update(Duration::from_secs(10)));
+
+        assert!(match_state.start(match_settings()));
+        assert_eq!(match_state.score.red_team, 0);
+        assert_eq!(match_state.kickoff_team, TeamKind::RedTeam);
+        assert_eq!(match_state.clock, Duration::ZERO);
+    }
+
+    #[test]
+    fn parked_hosts_hand_the_room_on() {
+        let mut network = Network::new(HashMap::new(), ServerConfig::default());
+        let room_id = network.open_room(String::from("Test"), false, RoomAccessSettings::default());
+        let (packet_sender, _packet_receiver) = unbounded();
+        let (host, guest) = (SocketAddr::from(([10, 0, 0, 1], 4000)), SocketAddr::from(([10, 0, 0, 2], 4000)));
+
+        for address in [host, guest] {
+            let handshake = connect(json!({ "protocol_version": PROTOCOL_VERSION, "nickname": "player", "join": { "room": room_id } }));
+            network.handle_message(&handshake, &address, &packet_sender).unwrap();
+        }
+        assert_eq!(network.rooms[&room_id].host, Some(PlayerId(1)));
+
+        let timeout = Message { kind: MessageKind::Timeout, payload: String::new() };
+        network.handle_message(&timeout, &host, &packet_sender).unwrap();
+        assert_eq!(network.rooms[&room_id].host, Some(PlayerId(2)));
+
+        let session_token = network.clients().find(|client| client.id == PlayerId(1)).unwrap().session_token.clone();
+        let resume = connect(json!({ "protocol_version": PROTOCOL_VERSION, "nickname": "player", "session_token": session_token }));
+        network.handle_message(&resume, &host, &packet_sender).unwrap();
+        assert_eq!(network.rooms[&room_id].host, Some(PlayerId(2)));
+
+        network.handle_message(&data(json!({ "action": Action::PlayerDisconnected })), &guest, &packet_sender).unwrap();
+        assert_eq!(network.rooms[&room_id].host, Some(PlayerId(1)));
+
+        // Nobody is left to take over, the parked host gets the role back once it resumes
+        network.handle_message(&timeout, &host, &packet_sender).unwrap();
+        assert_eq!(network.rooms[&room_id].host, None);
+        network.handle_message(&resume, &host, &packet_sender).unwrap();
+        assert_eq!(network.rooms[&room_id].host, Some(PlayerId(1)));
+
+        for room in network.rooms.values_mut() {
+            room.close();
+        }
+    }
+
+    fn held(up: bool, down: bool, left: bool, right: bool) -> PlayerInput {
+        PlayerInput { up, down, left, right, kick: false }
+    }
+
+    fn assert_close(actual: Vector<f32>, expected: Vector<f32>) {
+        assert!((actual - expected).norm() < 1e-3, "expected {:?}, got {:?}", expected, actual);
+    }
+
+    #[test]
+    fn held_keys_accelerate_players() {
+        let movement = MovementSettings::default();
+
+        let velocity = movement.next_velocity(Vector::zeros(), &held(false, false, false, true), 0.1);
+        assert_close(velocity, vector![60.0, 0.0]);
+
+        let velocity = movement.next_velocity(velocity, &held(false, false, false, true), 0.05);
+        assert_close(velocity, vector![90.0, 0.0]);
+    }
+
+    #[test]
+    fn players_do_not_exceed_the_max_speed() {
+        let movement = MovementSettings::default();
+        let mut velocity = Vector::zeros();
+
+        for _ in 0..120 {
+            velocity = movement.next_velocity(velocity, &held(true, false, false, false), 1.0 / 60.0);
+            assert!(velocity.norm() <= movement.max_speed + 1e-3);
+        }
+        assert_close(velocity, vector![0.0, -movement.max_speed]);
+
+        // Lowering the max speed on reload slows down players that are faster already
+        let velocity = movement.next_velocity(vector![300.0, 0.0], &held(false, false, false, false), 1.0 / 60.0);
+        assert_close(velocity, vector![movement.max_speed, 0.0]);
+    }
+
+    #[test]
+    fn released_keys_damp_players_to_a_stop() {
+        let movement = MovementSettings::default();
+
+        let velocity = movement.next_velocity(vector![100.0, 0.0], &PlayerInput::default(), 0.05);
+        assert_close(velocity, vector![60.0, 0.0]);
+
+        // A long step never turns the velocity around
+        let velocity = movement.next_velocity(velocity, &PlayerInput::default(), 1.0);
+        assert_eq!(velocity, Vector::zeros());
+
+        // Opposite keys cancel out like released ones
+        let velocity = movement.next_velocity(vector![0.0, 50.0], &held(true, true, false, false), 0.05);
+        assert_close(velocity, vector![0.0, 30.0]);
+    }
+
+    #[test]
+    fn diagonal_movement_is_not_faster() {
+        let movement = MovementSettings::default();
+        let mut velocity = Vector::zeros();
+
+        for _ in 0..120 {
+            velocity = movement.next_velocity(velocity, &held(false, true, false, true), 1.0 / 60.0);
+        }
+
+        let component = movement.max_speed / 2.0f32.sqrt();
+        assert_close(velocity, vector![component, component]);
+    }
+
+    fn client(id: u32, address: SocketAddr) -> Client {
+        Client {
+            id: PlayerId(id),
+            address,
+            nickname: String::from("player"),
+            team: TeamKind::SpecTeam,
+            codec: Codec::Json,
+            acked_tick: None,
+            input_sequence: None,
+            malformed_packets: 0,
+            session_token: String::from("token"),
+            disconnected_at: None,
+        }
+    }
+
+    #[test]
+    fn stale_and_duplicate_inputs_are_dropped() {
+        let address = SocketAddr::from(([10, 0, 0, 1], 4000));
+        let mut room = Room::open(RoomId(1), String::from("Test"), false, &ServerConfig::default());
+        room.add_client(client(1, address));
+
+        assert!(room.accept_input_sequence(&address, Some(10)));
+        assert!(!room.accept_input_sequence(&address, Some(10)));
+        assert!(!room.accept_input_sequence(&address, Some(9)));
+
+        // Lost inputs leave gaps, the next one that arrives is still applied
+        assert!(room.accept_input_sequence(&address, Some(15)));
+        assert!(!room.accept_input_sequence(&address, Some(12)));
+
+        // Clients built before sequencing never get their inputs dropped
+        assert!(room.accept_input_sequence(&address, None));
+        assert!(room.accept_input_sequence(&SocketAddr::from(([10, 0, 0, 2], 4000)), Some(1)));
+
+        room.close();
+    }
+
+    #[test]
+    fn input_sequences_wrap_around() {
+        let address = SocketAddr::from(([10, 0, 0, 1], 4000));
+        let mut room = Room::open(RoomId(1), String::from("Test"), false, &ServerConfig::default());
+        room.add_client(client(1, address));
+
+        assert!(room.accept_input_sequence(&address, Some(u32::MAX - 1)));
+        assert!(room.accept_input_sequence(&address, Some(u32::MAX)));
+        assert!(room.accept_input_sequence(&address, Some(0)));
+        assert!(room.accept_input_sequence(&address, Some(2)));
+        assert!(!room.accept_input_sequence(&address, Some(u32::MAX)));
+
+        room.close();
+    }
+
+    #[test]
+    fn newer_sequences_are_less_than_half_the_range_ahead() {
+        assert!(is_newer_sequence(1, 0));
+        assert!(is_newer_sequence(i32::MAX as u32, 0));
+        assert!(!is_newer_sequence(i32::MAX as u32 + 1, 0));
+        assert!(!is_newer_sequence(7, 7));
+        assert!(is_newer_sequence(3, u32::MAX - 3));
+    }
+
+    fn handshake_replies(packet_receiver: &Receiver<Packet>) -> Vec<HandshakeReply> {
+        packet_receiver.try_iter()
+            .filter_map(|packet| Message::decode(packet.payload()).ok())
+            .filter_map(|message| message.decode_payload::<HandshakeReply>().ok())
+            .collect()
+    }
+
+    #[test]
+    fn only_players_in_a_room_are_resumed() {
+        let address = SocketAddr::from(([10, 0, 0, 1], 4000));
+        let (mut network, packet_sender, packet_receiver) = connected_network(&address);
+        let session_token = network.clients().next().unwrap().session_token.clone();
+        let resume = connect(json!({ "protocol_version": PROTOCOL_VERSION, "nickname": "player", "session_token": session_token }));
+
+        // Still in the lobby, the client has to list the rooms again
+        handshake_replies(&packet_receiver);
+        network.handle_message(&resume, &address, &packet_sender).unwrap();
+        assert!(matches!(
+            handshake_replies(&packet_receiver)[..],
+            [HandshakeReply::Accepted { player_id: PlayerId(1), resumed: false, .. }]
+        ));
+
+        let room_id = network.open_room(String::from("Test"), false, RoomAccessSettings::default());
+        let join = data(json!({ "action": Action::JoinRoom, "room": room_id }));
+        network.handle_message(&join, &address, &packet_sender).unwrap();
+
+        network.handle_message(&resume, &address, &packet_sender).unwrap();
+        assert!(matches!(
+            handshake_replies(&packet_receiver)[..],
+            [HandshakeReply::Accepted { player_id: PlayerId(1), resumed: true, .. }]
+        ));
+
+        for room in network.rooms.values_mut() {
+            room.close();
+        }
+    }
+
+    #[test]
+    fn version_rejections_are_readable_without_the_acked_header() {
+        let mut network = Network::new(HashMap::new(), ServerConfig::default());
+        let (packet_sender, packet_receiver) = unbounded();
+        let address = SocketAddr::from(([10, 0, 0, 1], 4000));
+
+        // Clients from before the handshake only send their nickname
+        network.handle_message(&connect(json!({ "nickname": "old" })), &address, &packet_sender).unwrap();
+
+        let packet = packet_receiver.try_recv().unwrap();
+        assert_eq!(packet.delivery_guarantee(), laminar::DeliveryGuarantee::Unreliable);
+        assert_eq!(packet.order_guarantee(), laminar::OrderingGuarantee::None);
+
+        let reply: HandshakeReply = Message::decode(packet.payload()).unwrap().decode_payload().unwrap();
+        assert!(matches!(reply, HandshakeReply::Rejected { reason: RejectReason::UnsupportedProtocolVersion, .. }));
+    }
+}
@@ -0,0 +1,57 @@
+use shared::codec::{BodySnapshot, Codec, PlayerSnapshot, Precision, WorldSnapshot};
+use shared::message::{PlayerId, TeamKind};
+
+/// Header laminar puts in front of every unreliable packet.
+const PACKET_HEADER_SIZE: usize = 5;
+
+const PLAYERS: usize = 10;
+
+// Snapshot of a tick where the ball and every player is moving
+fn snapshot(tick: u64) -> WorldSnapshot {
+    let precision = Precision::default();
+    let players = (0..PLAYERS)
+        .map(|index| PlayerSnapshot {
+            id: PlayerId(index as u32 + 1),
+            team: Some(if index % 2 == 0 { TeamKind::RedTeam } else { TeamKind::BlueTeam }),
+            input_sequence: Some(4_200 + index as u32),
+            body: BodySnapshot::new((100.0 + 80.0 * index as f32, 150.0 + 25.0 * index as f32), (-60.0, 35.5), 0.0, 0.0, &precision),
+        })
+        .collect();
+
+    WorldSnapshot {
+        tick,
+        precision,
+        ball: BodySnapshot::new((512.375, 300.125), (120.25, -48.0), 1.25, -3.5, &precision),
+        players,
+    }
+}
+
+fn bytes_per_tick(codec: Codec) -> usize {
+    codec.encode_snapshot(&snapshot(123_456)).len() + PACKET_HEADER_SIZE
+}
+
+// Delta against the previous tick when only the ball and one player kept moving
+fn delta_bytes_per_tick(codec: Codec) -> usize {
+    let baseline = snapshot(123_454);
+    let mut current = snapshot(123_456);
+    current.ball = BodySnapshot::new((514.375, 299.125), (120.25, -48.0), 1.19, -3.5, &current.precision);
+    current.players[0].body = BodySnapshot::new((99.0, 150.5), (-60.0, 35.5), 0.0, 0.0, &current.precision);
+
+    codec.encode_delta(&current.delta(&baseline)).len() + PACKET_HEADER_SIZE
+}
+
+fn main() {
+    let json = bytes_per_tick(Codec::Json);
+    let binary = bytes_per_tick(Codec::Binary);
+
+    println!("bytes per tick and client with the ball and {} players moving", PLAYERS);
+    println!("  json:   {:>5}", json);
+    println!("  binary: {:>5} ({:.1}% of json)", binary, binary as f32 / json as f32 * 100.0);
+
+    let json_delta = delta_bytes_per_tick(Codec::Json);
+    let binary_delta = delta_bytes_per_tick(Codec::Binary);
+
+    println!("bytes per tick and client with a delta where the ball and 1 player moved");
+    println!("  json:   {:>5}", json_delta);
+    println!("  binary: {:>5} ({:.1}% of json)", binary_delta, binary_delta as f32 / json_delta as f32 * 100.0);
+}
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use shared::message::{LobbyRequest, Message, PlayerInput};
+
+// Every datagram the server receives goes through these decoders, none of them may panic
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::decode(data) {
+        let _ = message.decode_payload::<serde_json::Value>();
+        let _ = message.decode_payload::<PlayerInput>();
+        let _ = message.decode_payload::<LobbyRequest>();
+    }
+});
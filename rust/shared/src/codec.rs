@@ -0,0 +1,476 @@
+use std::collections::VecDeque;
+
+use bincode::Options;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+use crate::message::{Action, DecodeError, Message, MessageKind, PlayerId, Position, TeamKind};
+
+/// Capability a client lists in its handshake when it can read binary frames.
+pub const BINARY_CODEC: &str = "BINARY_CODEC";
+
+/// Upper bound for a single datagram, so a forged length prefix cannot make us allocate.
+pub const MAX_FRAME_SIZE: u64 = 65536;
+
+/// Number of fractional bits kept for each quantity, a value of 3 means steps of 1/8.
+/// Values are sent as variable length integers, so coarser precision also means fewer bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Precision {
+    pub position: u8,
+    pub velocity: u8,
+    pub rotation: u8,
+}
+
+impl Default for Precision {
+    fn default() -> Precision {
+        Precision {
+            position: 3,
+            velocity: 3,
+            rotation: 8,
+        }
+    }
+}
+
+fn quantize(value: f32, bits: u8) -> i32 {
+    (value * (1u32 << bits) as f32).round() as i32
+}
+
+fn dequantize(value: i32, bits: u8) -> f32 {
+    value as f32 / (1u32 << bits) as f32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct QuantizedVector {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl QuantizedVector {
+    pub fn new(x: f32, y: f32, bits: u8) -> QuantizedVector {
+        QuantizedVector {
+            x: quantize(x, bits),
+            y: quantize(y, bits),
+        }
+    }
+
+    pub fn to_position(self, bits: u8) -> Position {
+        Position {
+            x: dequantize(self.x, bits),
+            y: dequantize(self.y, bits),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BodySnapshot {
+    pub position: QuantizedVector,
+    pub velocity: QuantizedVector,
+    pub rotation: i32,
+    pub angular_velocity: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerSnapshot {
+    pub id: PlayerId,
+    pub team: Option<TeamKind>,
+    /// Last input of this player the server has applied, clients replay everything after it.
+    pub input_sequence: Option<u32>,
+    pub body: BodySnapshot,
+}
+
+/// State of one simulation step, every client gets a single one per tick.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorldSnapshot {
+    pub tick: u64,
+    pub precision: Precision,
+    pub ball: BodySnapshot,
+    pub players: Vec<PlayerSnapshot>,
+}
+
+/// Changes since a snapshot the client has acknowledged. Unchanged bodies are left out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotDelta {
+    pub tick: u64,
+    pub baseline: u64,
+    pub precision: Precision,
+    pub ball: Option<BodySnapshot>,
+    pub players: Vec<PlayerSnapshot>,
+    pub removed: Vec<PlayerId>,
+}
+
+impl BodySnapshot {
+    pub fn new(position: (f32, f32), velocity: (f32, f32), rotation: f32, angular_velocity: f32, precision: &Precision) -> BodySnapshot {
+        BodySnapshot {
+            position: QuantizedVector::new(position.0, position.1, precision.position),
+            velocity: QuantizedVector::new(velocity.0, velocity.1, precision.velocity),
+            rotation: quantize(rotation, precision.rotation),
+            angular_velocity: quantize(angular_velocity, precision.rotation),
+        }
+    }
+
+    fn to_json(&self, precision: &Precision) -> serde_json::Value {
+        json!({
+            "position": self.position.to_position(precision.position),
+            "velocity": self.velocity.to_position(precision.velocity),
+            "rotation": dequantize(self.rotation, precision.rotation),
+            "angular_velocity": dequantize(self.angular_velocity, precision.rotation),
+        })
+    }
+}
+
+impl PlayerSnapshot {
+    fn to_json(&self, precision: &Precision) -> serde_json::Value {
+        let mut body = self.body.to_json(precision);
+        body["id"] = json!(self.id);
+        body["team"] = json!(self.team);
+        body["input_sequence"] = json!(self.input_sequence);
+        body
+    }
+}
+
+impl WorldSnapshot {
+    pub fn delta(&self, baseline: &WorldSnapshot) -> SnapshotDelta {
+        SnapshotDelta {
+            tick: self.tick,
+            baseline: baseline.tick,
+            precision: self.precision,
+            ball: if self.ball != baseline.ball { Some(self.ball.clone()) } else { None },
+            players: self.players.iter()
+                .filter(|player| !baseline.players.contains(player))
+                .cloned()
+                .collect(),
+            removed: baseline.players.iter()
+                .filter(|old_player| !self.players.iter().any(|player| player.id == old_player.id))
+                .map(|old_player| old_player.id)
+                .collect(),
+        }
+    }
+
+    /// `WORLD_SNAPSHOT` payload for clients without the binary codec, quantized values are sent as floats again.
+    pub fn to_json(&self) -> String {
+        let players: Vec<serde_json::Value> = self.players.iter()
+            .map(|player| player.to_json(&self.precision))
+            .collect();
+
+        json!({
+            "action": Action::WorldSnapshot,
+            "tick": self.tick,
+            "ball": self.ball.to_json(&self.precision),
+            "players": players,
+        }).to_string()
+    }
+}
+
+impl SnapshotDelta {
+    pub fn apply(&self, baseline: &WorldSnapshot) -> WorldSnapshot {
+        let mut players: Vec<PlayerSnapshot> = baseline.players.iter()
+            .filter(|old_player| !self.removed.contains(&old_player.id))
+            .filter(|old_player| !self.players.iter().any(|player| player.id == old_player.id))
+            .cloned()
+            .collect();
+        players.extend(self.players.iter().cloned());
+        players.sort_by_key(|player| player.id);
+
+        WorldSnapshot {
+            tick: self.tick,
+            precision: self.precision,
+            ball: self.ball.clone().unwrap_or_else(|| baseline.ball.clone()),
+            players,
+        }
+    }
+
+    /// `WORLD_SNAPSHOT_DELTA` payload for clients without the binary codec, the ball is null when it did not change.
+    pub fn to_json(&self) -> String {
+        json!({
+            "action": Action::WorldSnapshotDelta,
+            "tick": self.tick,
+            "baseline": self.baseline,
+            "ball": self.ball.as_ref().map(|ball| ball.to_json(&self.precision)),
+            "players": self.players.iter().map(|player| player.to_json(&self.precision)).collect::<Vec<serde_json::Value>>(),
+            "removed": self.removed,
+        }).to_string()
+    }
+}
+
+/// Snapshots sent recently, deltas are computed against the one a client acknowledged last.
+#[derive(Debug, Clone)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<WorldSnapshot>,
+    capacity: usize,
+}
+
+impl SnapshotHistory {
+    pub fn new(capacity: usize) -> SnapshotHistory {
+        SnapshotHistory {
+            snapshots: VecDeque::with_capacity(capacity + 1),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, snapshot: WorldSnapshot) {
+        self.snapshots.push_back(snapshot);
+        if self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Baseline for a delta of `snapshot`. A client gets a full snapshot instead when it acknowledged
+    /// nothing yet, its snapshot already left the history or was quantized with another precision.
+    pub fn baseline(&self, acked_tick: Option<u64>, snapshot: &WorldSnapshot) -> Option<&WorldSnapshot> {
+        acked_tick
+            .and_then(|acked_tick| self.snapshots.iter().find(|baseline| baseline.tick == acked_tick))
+            .filter(|baseline| baseline.precision == snapshot.precision)
+    }
+}
+
+/// Everything a binary client receives. Lifecycle messages are rare and keep their
+/// JSON payload, only the per-tick snapshots get a compact layout.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Frame {
+    Message(Message),
+    Snapshot(WorldSnapshot),
+    Delta(SnapshotDelta),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Json,
+    Binary,
+}
+
+impl Codec {
+    pub fn negotiate(capabilities: &[String]) -> Codec {
+        if capabilities.iter().any(|capability| capability == BINARY_CODEC) {
+            Codec::Binary
+        } else {
+            Codec::Json
+        }
+    }
+
+    pub fn encode_message(&self, message: &Message) -> Vec<u8> {
+        match self {
+            Codec::Json => serde_json::to_vec(message).unwrap(),
+            Codec::Binary => encode_frame(&Frame::Message(message.clone())),
+        }
+    }
+
+    pub fn encode_snapshot(&self, snapshot: &WorldSnapshot) -> Vec<u8> {
+        match self {
+            Codec::Json => self.encode_message(&Message {
+                kind: MessageKind::Data,
+                payload: snapshot.to_json(),
+            }),
+            Codec::Binary => encode_frame(&Frame::Snapshot(snapshot.clone())),
+        }
+    }
+
+    pub fn encode_delta(&self, delta: &SnapshotDelta) -> Vec<u8> {
+        match self {
+            Codec::Json => self.encode_message(&Message {
+                kind: MessageKind::Data,
+                payload: delta.to_json(),
+            }),
+            Codec::Binary => encode_frame(&Frame::Delta(delta.clone())),
+        }
+    }
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_FRAME_SIZE)
+}
+
+fn encode_frame(frame: &Frame) -> Vec<u8> {
+    options().serialize(frame).unwrap()
+}
+
+pub fn decode_frame(bytes: &[u8]) -> Result<Frame, DecodeError> {
+    options().deserialize(bytes).map_err(|error| DecodeError::Malformed(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(x: f32, y: f32, precision: &Precision) -> BodySnapshot {
+        BodySnapshot::new((x, y), (1.5, -2.0), 0.25, 0.5, precision)
+    }
+
+    fn snapshot() -> WorldSnapshot {
+        let precision = Precision::default();
+
+        WorldSnapshot {
+            tick: 42,
+            precision,
+            ball: body(485.0, 290.0, &precision),
+            players: vec![PlayerSnapshot {
+                id: PlayerId(1),
+                team: Some(TeamKind::RedTeam),
+                input_sequence: Some(3),
+                body: body(100.25, 200.5, &precision),
+            }],
+        }
+    }
+
+    fn message() -> Message {
+        Message {
+            kind: MessageKind::Data,
+            payload: String::from(r#"{"action":"GOAL","team":"RedTeam"}"#),
+        }
+    }
+
+    #[test]
+    fn json_codec_round_trips_messages() {
+        let decoded = Message::decode(&Codec::Json.encode_message(&message())).unwrap();
+
+        assert!(matches!(decoded.kind, MessageKind::Data));
+        assert_eq!(decoded.payload, message().payload);
+    }
+
+    #[test]
+    fn json_codec_sends_dequantized_snapshots() {
+        let decoded = Message::decode(&Codec::Json.encode_snapshot(&snapshot())).unwrap();
+        let payload: serde_json::Value = decoded.decode_payload().unwrap();
+
+        assert_eq!(payload["action"], "WORLD_SNAPSHOT");
+        assert_eq!(payload["tick"], 42);
+        assert_eq!(payload["players"][0]["id"], 1);
+        assert_eq!(payload["players"][0]["team"], "RedTeam");
+        assert_eq!(payload["players"][0]["position"]["x"], 100.25);
+        assert_eq!(payload["players"][0]["position"]["y"], 200.5);
+    }
+
+    #[test]
+    fn binary_codec_round_trips_messages() {
+        match decode_frame(&Codec::Binary.encode_message(&message())).unwrap() {
+            Frame::Message(decoded) => assert_eq!(decoded.payload, message().payload),
+            frame => panic!("expected a message, got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn binary_codec_round_trips_snapshots_and_deltas() {
+        match decode_frame(&Codec::Binary.encode_snapshot(&snapshot())).unwrap() {
+            Frame::Snapshot(decoded) => assert_eq!(decoded, snapshot()),
+            frame => panic!("expected a snapshot, got {:?}", frame),
+        }
+
+        let delta = snapshot().delta(&snapshot());
+        match decode_frame(&Codec::Binary.encode_delta(&delta)).unwrap() {
+            Frame::Delta(decoded) => assert_eq!(decoded, delta),
+            frame => panic!("expected a delta, got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn binary_snapshots_are_smaller_than_json() {
+        assert!(Codec::Binary.encode_snapshot(&snapshot()).len() < Codec::Json.encode_snapshot(&snapshot()).len());
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let encoded = Codec::Binary.encode_snapshot(&snapshot());
+
+        for length in 0..encoded.len() {
+            assert!(decode_frame(&encoded[..length]).is_err(), "accepted a frame cut at {} bytes", length);
+        }
+    }
+
+    #[test]
+    fn forged_lengths_beyond_the_frame_limit_are_rejected() {
+        // Frame::Message, MessageKind::Connect, then a payload claiming u64::MAX bytes
+        let mut forged = vec![0, 0, 253];
+        forged.extend_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(matches!(decode_frame(&forged), Err(DecodeError::Malformed(_))));
+    }
+
+    fn player(id: u32, x: f32, precision: &Precision) -> PlayerSnapshot {
+        PlayerSnapshot {
+            id: PlayerId(id),
+            team: Some(TeamKind::BlueTeam),
+            input_sequence: None,
+            body: body(x, 0.0, precision),
+        }
+    }
+
+    fn world(tick: u64, precision: Precision, players: Vec<PlayerSnapshot>) -> WorldSnapshot {
+        WorldSnapshot {
+            tick,
+            precision,
+            ball: body(485.0, 290.0, &precision),
+            players,
+        }
+    }
+
+    #[test]
+    fn delta_of_an_unchanged_snapshot_is_empty() {
+        let baseline = snapshot();
+        let delta = WorldSnapshot { tick: 43, ..snapshot() }.delta(&baseline);
+
+        assert_eq!(delta.baseline, 42);
+        assert_eq!(delta.ball, None);
+        assert!(delta.players.is_empty());
+        assert!(delta.removed.is_empty());
+        assert_eq!(delta.apply(&baseline), WorldSnapshot { tick: 43, ..snapshot() });
+    }
+
+    #[test]
+    fn delta_applied_to_its_baseline_gives_the_snapshot_back() {
+        let precision = Precision::default();
+        let baseline = world(10, precision, vec![player(1, 10.0, &precision), player(2, 20.0, &precision), player(3, 30.0, &precision)]);
+
+        // Player 1 is unchanged, 2 moved, 3 left and 4 joined
+        let mut current = world(11, precision, vec![player(1, 10.0, &precision), player(2, 25.0, &precision), player(4, 40.0, &precision)]);
+        current.ball = body(490.0, 290.0, &precision);
+
+        let delta = current.delta(&baseline);
+
+        assert_eq!(delta.players.iter().map(|player| player.id).collect::<Vec<PlayerId>>(), vec![PlayerId(2), PlayerId(4)]);
+        assert_eq!(delta.removed, vec![PlayerId(3)]);
+        assert!(delta.ball.is_some());
+        assert_eq!(delta.apply(&baseline), current);
+    }
+
+    #[test]
+    fn history_gives_the_acknowledged_snapshot_as_baseline() {
+        let precision = Precision::default();
+        let mut history = SnapshotHistory::new(4);
+        for tick in 0..4 {
+            history.push(world(tick, precision, vec![]));
+        }
+
+        let current = world(4, precision, vec![]);
+        assert_eq!(history.baseline(Some(2), &current).map(|baseline| baseline.tick), Some(2));
+        assert_eq!(history.baseline(None, &current), None);
+    }
+
+    #[test]
+    fn history_falls_back_to_a_full_snapshot_once_the_baseline_is_dropped() {
+        let precision = Precision::default();
+        let mut history = SnapshotHistory::new(4);
+        for tick in 0..6 {
+            history.push(world(tick, precision, vec![]));
+        }
+
+        let current = world(6, precision, vec![]);
+        assert_eq!(history.baseline(Some(1), &current), None);
+        assert_eq!(history.baseline(Some(2), &current).map(|baseline| baseline.tick), Some(2));
+    }
+
+    #[test]
+    fn history_falls_back_to_a_full_snapshot_when_the_precision_changed() {
+        let precision = Precision::default();
+        let mut history = SnapshotHistory::new(4);
+        history.push(world(0, precision, vec![]));
+
+        let current = world(1, Precision { position: 4, ..precision }, vec![]);
+        assert_eq!(history.baseline(Some(0), &current), None);
+    }
+
+    #[test]
+    fn codec_is_negotiated_from_capabilities() {
+        assert_eq!(Codec::negotiate(&[]), Codec::Json);
+        assert_eq!(Codec::negotiate(&[String::from(BINARY_CODEC)]), Codec::Binary);
+    }
+}
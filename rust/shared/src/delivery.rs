@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+
+use laminar::Packet;
+
+use crate::message::Action;
+
+/// Lifecycle and roster events share one ordered stream, a join must never arrive after the leave.
+pub const EVENT_STREAM: u8 = 0;
+/// Snapshots are sequenced on their own, an older snapshot is worthless once a newer one arrived.
+pub const SNAPSHOT_STREAM: u8 = 1;
+pub const INPUT_STREAM: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    ReliableOrdered(u8),
+    ReliableUnordered,
+    UnreliableSequenced(u8),
+    // Bare standard header, for replies to clients that may not read the other headers of this protocol
+    Unreliable,
+}
+
+impl Delivery {
+    /// Policy both sides follow for every action, anything unknown is treated as a state change.
+    /// The Godot client is exempt for its own reliable-ordered events and sends them unreliable, see `packet_header` there.
+    pub fn of(action: Action) -> Delivery {
+        match action {
+            Action::WorldSnapshot | Action::WorldSnapshotDelta => Delivery::UnreliableSequenced(SNAPSHOT_STREAM),
+            Action::PlayerInput => Delivery::UnreliableSequenced(INPUT_STREAM),
+            // Acks go out every tick, so they also carry the acknowledgements of reliable events back
+            Action::SnapshotAck => Delivery::ReliableUnordered,
+            _ => Delivery::ReliableOrdered(EVENT_STREAM),
+        }
+    }
+
+    pub fn packet(self, address: SocketAddr, payload: Vec<u8>) -> Packet {
+        match self {
+            Delivery::ReliableOrdered(stream) => Packet::reliable_ordered(address, payload, Some(stream)),
+            Delivery::ReliableUnordered => Packet::reliable_unordered(address, payload),
+            Delivery::UnreliableSequenced(stream) => Packet::unreliable_sequenced(address, payload, Some(stream)),
+            Delivery::Unreliable => Packet::unreliable(address, payload),
+        }
+    }
+}
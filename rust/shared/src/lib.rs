@@ -1,2 +1,4 @@
 pub mod message;
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod codec;
+pub mod delivery;
\ No newline at end of file
@@ -1,26 +1,522 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 
-#[derive(Serialize, Deserialize, Debug)]
+pub const PROTOCOL_VERSION: u32 = 6;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageKind {
     Connect,
     Timeout,
     Data
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub kind: MessageKind,
     pub payload: String,
 }
 
+impl Message {
+    pub fn decode(bytes: &[u8]) -> Result<Message, DecodeError> {
+        serde_json::from_slice(bytes).map_err(|error| DecodeError::Malformed(error.to_string()))
+    }
+
+    pub fn decode_payload<T: DeserializeOwned>(&self) -> Result<T, DecodeError> {
+        serde_json::from_str(&self.payload).map_err(|error| DecodeError::Malformed(error.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    Malformed(String),
+    MissingField(&'static str),
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            DecodeError::MissingField(field) => write!(f, "missing field `{}`", field),
+            DecodeError::InvalidField(field) => write!(f, "invalid field `{}`", field),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Number the server gives every connection, socket addresses never leave the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct PlayerId(pub u32);
+
+impl fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Every room runs its own match, packets of a player are routed to the room it joined.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct RoomId(pub u32);
+
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Team of a player, everyone joins a room as a spectator and picks a team from there.
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TeamKind {
+    RedTeam,
+    SpecTeam,
+    BlueTeam
+}
+
+impl TeamKind {
+    pub const ALL: [TeamKind; 3] = [TeamKind::RedTeam, TeamKind::SpecTeam, TeamKind::BlueTeam];
+
+    /// Team that kicks off after this one scored, spectators have no opponent.
+    pub fn opponent(&self) -> TeamKind {
+        match self {
+            TeamKind::RedTeam => TeamKind::BlueTeam,
+            TeamKind::BlueTeam => TeamKind::RedTeam,
+            TeamKind::SpecTeam => TeamKind::SpecTeam,
+        }
+    }
+}
+
+/// Name of every message sent either way, as it appears in the `action` field of the payload.
+/// The delivery policy is keyed by it, so a misspelled action does not compile.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Action {
+    // Sent by clients
+    ChangePlayerTeam,
+    PlayerInput,
+    SnapshotAck,
+    StartMatch,
+    StopMatch,
+    PlayerDisconnected,
+    ListRooms,
+    CreateRoom,
+    JoinRoom,
+    LeaveRoom,
+    // Sent by the server
+    HandshakeAccepted,
+    HandshakeRejected,
+    PlayerAddAck,
+    ChangePlayerTeamAck,
+    PlayerDisconnectAck,
+    WorldSnapshot,
+    WorldSnapshotDelta,
+    MatchPhase,
+    MatchControlRejected,
+    Goal,
+    SettingsChanged,
+    RoomList,
+    RoomJoined,
+    RoomJoinRejected,
+    RoomLeft,
+}
+
+/// Payload of a `Connect` message. Clients built before the handshake existed only send
+/// a nickname, so they end up with protocol version 0 and get a rejection instead of silence,
+/// sent with the bare packet header they know how to strip.
+/// A client that lost its connection sends the token of its session to get its player back.
+/// A client that already knows its room joins it right away, a wrong password rejects the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub client_build: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join: Option<RoomAccess>,
+    pub nickname: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action")]
+pub enum HandshakeReply {
+    #[serde(rename = "HANDSHAKE_ACCEPTED")]
+    Accepted {
+        protocol_version: u32,
+        player_id: PlayerId,
+        session_token: String,
+        // Set when the session token was recognized and the player is back in its room,
+        // a player that was waiting in the lobby keeps its id but has to pick a room again
+        resumed: bool,
+        capabilities: Vec<String>,
+    },
+    #[serde(rename = "HANDSHAKE_REJECTED")]
+    Rejected {
+        protocol_version: u32,
+        reason: RejectReason,
+    },
+}
+
+impl HandshakeReply {
+    pub fn action(&self) -> Action {
+        match self {
+            HandshakeReply::Accepted { .. } => Action::HandshakeAccepted,
+            HandshakeReply::Rejected { .. } => Action::HandshakeRejected,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    UnsupportedProtocolVersion,
+    AlreadyConnected,
+    // The room asked for in the handshake did not let the player in
+    Room(JoinRejectReason),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::UnsupportedProtocolVersion => write!(f, "unsupported protocol version"),
+            RejectReason::AlreadyConnected => write!(f, "already connected"),
+            RejectReason::Room(reason) => write!(f, "cannot join room: {}", reason),
+        }
+    }
+}
+
+/// Room a player wants to get into. Invite-only rooms are not listed, they are found by their code.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RoomAccess {
+    #[serde(default)]
+    pub room: Option<RoomId>,
+    #[serde(default)]
+    pub invite_code: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Sent by players in the lobby, a player only gets a body once it joined a room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action")]
+pub enum LobbyRequest {
+    #[serde(rename = "LIST_ROOMS")]
+    ListRooms,
+    #[serde(rename = "CREATE_ROOM")]
+    CreateRoom {
+        // The server names the room after its creator when left out
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        // Hidden from the room list, players join with the invite code the server generates
+        #[serde(default)]
+        invite_only: bool,
+    },
+    #[serde(rename = "JOIN_ROOM")]
+    JoinRoom(RoomAccess),
+    #[serde(rename = "LEAVE_ROOM")]
+    LeaveRoom,
+}
+
+impl LobbyRequest {
+    /// Actions answered by the lobby whether or not the player is in a room.
+    pub fn handles(action: Action) -> bool {
+        matches!(action, Action::ListRooms | Action::CreateRoom | Action::JoinRoom | Action::LeaveRoom)
+    }
+}
+
+/// Phase of the match a room is playing, the server moves it along and clients only show it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MatchPhase {
+    Lobby,
+    Kickoff,
+    Playing,
+    GoalScored,
+    Halftime,
+    FullTime,
+}
+
+impl MatchPhase {
+    /// Players stand still until the kickoff countdown ran out.
+    pub fn allows_movement(&self) -> bool {
+        !matches!(self, MatchPhase::Kickoff)
+    }
+
+    pub fn allows_kick(&self) -> bool {
+        matches!(self, MatchPhase::Lobby | MatchPhase::Playing)
+    }
+}
+
+/// Number of players in each team of a room, every team is listed even when nobody is in it.
+pub type TeamCounts = BTreeMap<TeamKind, u32>;
+
+/// What the room list shows about a room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub name: String,
+    pub players: TeamCounts,
+    pub has_password: bool,
+    pub match_phase: MatchPhase,
+    pub map: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action")]
+pub enum LobbyReply {
+    #[serde(rename = "ROOM_LIST")]
+    RoomList {
+        rooms: Vec<RoomInfo>,
+    },
+    #[serde(rename = "ROOM_JOINED")]
+    Joined {
+        room: RoomInfo,
+        // Given to everyone in an invite-only room, so they can invite others
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        invite_code: Option<String>,
+    },
+    #[serde(rename = "ROOM_JOIN_REJECTED")]
+    JoinRejected {
+        // Missing when no room was asked for, e.g. a room could not be created
+        room: Option<RoomId>,
+        reason: JoinRejectReason,
+    },
+    #[serde(rename = "ROOM_LEFT")]
+    Left {
+        room: RoomId,
+    },
+}
+
+impl LobbyReply {
+    pub fn action(&self) -> Action {
+        match self {
+            LobbyReply::RoomList { .. } => Action::RoomList,
+            LobbyReply::Joined { .. } => Action::RoomJoined,
+            LobbyReply::JoinRejected { .. } => Action::RoomJoinRejected,
+            LobbyReply::Left { .. } => Action::RoomLeft,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum JoinRejectReason {
+    RoomNotFound,
+    TooManyRooms,
+    WrongPassword,
+    // The room can only be joined with its invite code
+    InviteOnly,
+    InvalidInviteCode,
+    // Too many wrong passwords or invite codes came from this address, it has to wait before trying again
+    TooManyAttempts,
+}
+
+impl fmt::Display for JoinRejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinRejectReason::RoomNotFound => write!(f, "room not found"),
+            JoinRejectReason::TooManyRooms => write!(f, "too many rooms"),
+            JoinRejectReason::WrongPassword => write!(f, "wrong password"),
+            JoinRejectReason::InviteOnly => write!(f, "invite only"),
+            JoinRejectReason::InvalidInviteCode => write!(f, "invalid invite code"),
+            JoinRejectReason::TooManyAttempts => write!(f, "too many attempts"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Position {
     pub x: f32,
-    pub y: f32 
+    pub y: f32
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Behavior {
-    pub action: String,
-    pub position: Option<Position>
-}
\ No newline at end of file
+/// Keys a client is holding, sent every frame. The server turns them into velocity,
+/// a kick happens when `kick` goes from released to held.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlayerInput {
+    #[serde(default)]
+    pub up: bool,
+    #[serde(default)]
+    pub down: bool,
+    #[serde(default)]
+    pub left: bool,
+    #[serde(default)]
+    pub right: bool,
+    #[serde(default)]
+    pub kick: bool,
+}
+
+impl PlayerInput {
+    /// Direction of the held keys, opposite keys cancel out.
+    pub fn direction(&self) -> (f32, f32) {
+        let axis = |negative: bool, positive: bool| positive as i8 as f32 - negative as i8 as f32;
+        (axis(self.left, self.right), axis(self.up, self.down))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn connect(payload: serde_json::Value) -> Message {
+        Message {
+            kind: MessageKind::Connect,
+            payload: payload.to_string(),
+        }
+    }
+
+    #[test]
+    fn legacy_connect_decodes_to_protocol_version_zero() {
+        let handshake: Handshake = connect(json!({ "nickname": "old" })).decode_payload().unwrap();
+
+        assert_eq!(handshake.protocol_version, 0);
+        assert_eq!(handshake.nickname, "old");
+        assert!(handshake.capabilities.is_empty());
+        assert_eq!(handshake.session_token, None);
+        assert_eq!(handshake.join, None);
+    }
+
+    #[test]
+    fn handshake_round_trips_through_a_connect_message() {
+        let handshake = Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: String::from("test"),
+            capabilities: vec![String::from("binary")],
+            session_token: Some(String::from("token")),
+            join: Some(RoomAccess {
+                room: Some(RoomId(2)),
+                invite_code: None,
+                password: Some(String::from("secret")),
+            }),
+            nickname: String::from("player"),
+        };
+
+        let message = connect(serde_json::to_value(&handshake).unwrap());
+        assert_eq!(message.decode_payload::<Handshake>().unwrap(), handshake);
+    }
+
+    #[test]
+    fn handshake_without_a_nickname_is_malformed() {
+        let decoded = connect(json!({ "protocol_version": PROTOCOL_VERSION })).decode_payload::<Handshake>();
+
+        assert!(matches!(decoded, Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn accepted_reply_matches_the_client_wire_format() {
+        let reply = HandshakeReply::Accepted {
+            protocol_version: PROTOCOL_VERSION,
+            player_id: PlayerId(7),
+            session_token: String::from("token"),
+            resumed: false,
+            capabilities: vec![],
+        };
+
+        assert_eq!(reply.action(), Action::HandshakeAccepted);
+        assert_eq!(serde_json::to_value(&reply).unwrap(), json!({
+            "action": "HANDSHAKE_ACCEPTED",
+            "protocol_version": PROTOCOL_VERSION,
+            "player_id": 7,
+            "session_token": "token",
+            "resumed": false,
+            "capabilities": []
+        }));
+    }
+
+    #[test]
+    fn rejected_reply_matches_the_client_wire_format() {
+        let reply = HandshakeReply::Rejected {
+            protocol_version: PROTOCOL_VERSION,
+            reason: RejectReason::UnsupportedProtocolVersion,
+        };
+
+        assert_eq!(reply.action(), Action::HandshakeRejected);
+        assert_eq!(serde_json::to_value(&reply).unwrap(), json!({
+            "action": "HANDSHAKE_REJECTED",
+            "protocol_version": PROTOCOL_VERSION,
+            "reason": "UnsupportedProtocolVersion"
+        }));
+
+        let room_reason = serde_json::to_value(RejectReason::Room(JoinRejectReason::WrongPassword)).unwrap();
+        assert_eq!(room_reason, json!({ "Room": "WrongPassword" }));
+    }
+
+    #[test]
+    fn actions_match_the_tag_of_their_reply() {
+        let replies = vec![
+            LobbyReply::RoomList { rooms: vec![] },
+            LobbyReply::JoinRejected { room: None, reason: JoinRejectReason::TooManyRooms },
+            LobbyReply::Left { room: RoomId(1) },
+        ];
+
+        for reply in replies {
+            assert_eq!(serde_json::to_value(&reply).unwrap()["action"], serde_json::to_value(reply.action()).unwrap());
+        }
+
+        assert_eq!(serde_json::to_value(Action::WorldSnapshotDelta).unwrap(), "WORLD_SNAPSHOT_DELTA");
+        assert_eq!(serde_json::to_value(Action::ChangePlayerTeamAck).unwrap(), "CHANGE_PLAYER_TEAM_ACK");
+    }
+
+    #[test]
+    fn lobby_requests_are_tagged_with_their_action() {
+        let requests = vec![
+            (LobbyRequest::ListRooms, Action::ListRooms),
+            (LobbyRequest::CreateRoom { name: None, password: None, invite_only: true }, Action::CreateRoom),
+            (LobbyRequest::JoinRoom(RoomAccess { room: Some(RoomId(1)), ..RoomAccess::default() }), Action::JoinRoom),
+            (LobbyRequest::LeaveRoom, Action::LeaveRoom),
+        ];
+
+        for (request, action) in requests {
+            let encoded = serde_json::to_value(&request).unwrap();
+
+            assert!(LobbyRequest::handles(action));
+            assert_eq!(encoded["action"], serde_json::to_value(action).unwrap());
+            assert_eq!(serde_json::from_value::<LobbyRequest>(encoded).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn room_actions_are_not_lobby_requests() {
+        let input = json!({ "action": Action::PlayerInput, "up": true });
+
+        assert!(!LobbyRequest::handles(Action::PlayerInput));
+        assert!(serde_json::from_value::<LobbyRequest>(input).is_err());
+    }
+
+    #[test]
+    fn room_info_lists_every_team_and_the_match_phase() {
+        let room = RoomInfo {
+            id: RoomId(1),
+            name: String::from("Main"),
+            players: TeamKind::ALL.iter().map(|team| (team.clone(), (*team == TeamKind::RedTeam) as u32)).collect(),
+            has_password: false,
+            match_phase: MatchPhase::GoalScored,
+            map: String::from("Classic"),
+        };
+
+        let encoded = serde_json::to_value(&room).unwrap();
+        assert_eq!(encoded["players"], json!({ "RedTeam": 1, "SpecTeam": 0, "BlueTeam": 0 }));
+        assert_eq!(encoded["match_phase"], "GoalScored");
+        assert_eq!(serde_json::from_value::<RoomInfo>(encoded).unwrap(), room);
+    }
+
+    #[test]
+    fn replies_decode_back_into_the_same_variant() {
+        let reply = HandshakeReply::Rejected {
+            protocol_version: PROTOCOL_VERSION,
+            reason: RejectReason::AlreadyConnected,
+        };
+
+        let encoded = serde_json::to_string(&reply).unwrap();
+        assert_eq!(serde_json::from_str::<HandshakeReply>(&encoded).unwrap(), reply);
+    }
+}